@@ -0,0 +1,146 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! `device-scanner` -- inspect the state of `device-scanner-daemon`.
+
+mod render;
+
+use device_scanner_client::Client;
+use device_types::DevicePath;
+use futures::StreamExt;
+use std::{fs, path::PathBuf, process::exit};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "device-scanner", about = "Inspect device-scanner-daemon state")]
+struct Opts {
+    /// Print JSON instead of a table
+    #[structopt(long, global = true)]
+    json: bool,
+
+    /// Path to the device-scanner socket
+    #[structopt(
+        long,
+        global = true,
+        default_value = "/var/run/device-scanner.sock",
+        parse(from_os_str)
+    )]
+    socket: PathBuf,
+
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    /// Show the current device tree (default)
+    Show,
+    /// Redraw the device tree every time it changes
+    Watch,
+    /// Print the device graph as a node table and edge list, in JSON
+    Graph,
+    /// List the mounts known to the daemon
+    Mounts,
+    /// Print every command the daemon accepts, as it arrives
+    Monitor,
+    /// Show daemon counters
+    Stats,
+    /// Show the device known by the given path, along with its children
+    Find {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts = Opts::from_args();
+
+    let client = Client::new(opts.socket);
+
+    match opts.cmd.unwrap_or(Cmd::Show) {
+        Cmd::Show => {
+            let graph = client.get_graph().await?;
+
+            if opts.json {
+                println!("{}", serde_json::to_string(&graph)?);
+            } else {
+                print!("{}", render::render_tree(&graph));
+            }
+        }
+        Cmd::Watch => {
+            let s = client.stream();
+
+            futures::pin_mut!(s);
+
+            while let Some(graph) = s.next().await {
+                if opts.json {
+                    println!("{}", serde_json::to_string(&graph)?);
+                } else {
+                    print!("\x1b[2J\x1b[H{}", render::render_tree(&graph));
+                }
+            }
+        }
+        Cmd::Graph => {
+            let graph = client.get_node_graph().await?;
+
+            println!("{}", serde_json::to_string(&graph)?);
+        }
+        Cmd::Mounts => {
+            let mounts = client.get_mounts().await?;
+
+            if opts.json {
+                println!("{}", serde_json::to_string(&mounts)?);
+            } else {
+                print!("{}", render::render_mounts(&mounts));
+            }
+        }
+        Cmd::Monitor => {
+            let s = client.monitor().await?;
+
+            futures::pin_mut!(s);
+
+            while let Some(x) = s.next().await {
+                let x = x?;
+
+                if opts.json {
+                    println!("{}", serde_json::to_string(&x)?);
+                } else {
+                    println!("{}", render::render_event(&x));
+                }
+            }
+        }
+        Cmd::Stats => {
+            let stats = client.get_stats().await?;
+
+            if opts.json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                println!("stale uevents: {}", stats.stale_uevents);
+            }
+        }
+        Cmd::Find { path } => {
+            let graph = client.get_graph().await?;
+
+            // Also try the resolved path, so any symlink to a device node can be used.
+            let device = render::find_by_path(&graph, &DevicePath(path.clone())).or_else(|| {
+                fs::canonicalize(&path)
+                    .ok()
+                    .and_then(|p| render::find_by_path(&graph, &DevicePath(p)))
+            });
+
+            match device {
+                Some(x) if opts.json => println!("{}", serde_json::to_string(x)?),
+                Some(x) => print!("{}", render::render_tree(x)),
+                None => {
+                    eprintln!("No device found for {}", path.display());
+
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,614 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Renders device graphs and mounts as tables.
+
+use device_types::{
+    client::ClientMount,
+    devices::{Device, Mounts, Root},
+    monitor::MonitorEvent,
+    mount::Mount,
+    DevicePath,
+};
+use std::{path::Path, time::SystemTime};
+
+const HEADERS: [&str; 7] = [
+    "NAME",
+    "TYPE",
+    "SIZE",
+    "FSTYPE",
+    "MOUNTPOINT",
+    "POOL",
+    "SERIAL",
+];
+
+type Row = Vec<String>;
+
+/// Formats a size in bytes using binary prefixes, like `lsblk`.
+pub fn human_size(x: u64) -> String {
+    let units = ["B", "K", "M", "G", "T", "P", "E"];
+
+    let mut size = x as f64;
+    let mut idx = 0;
+
+    while size >= 1024.0 && idx < units.len() - 1 {
+        size /= 1024.0;
+        idx += 1;
+    }
+
+    if idx == 0 {
+        format!("{}{}", x, units[idx])
+    } else if size < 10.0 {
+        format!("{:.1}{}", size, units[idx])
+    } else {
+        format!("{:.0}{}", size, units[idx])
+    }
+}
+
+fn file_name(p: &Path) -> String {
+    p.file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_else(|| p.to_string_lossy().to_string())
+}
+
+/// Picks the kernel name (i.e. `/dev/md0`) out of a set of device paths.
+fn dev_name<'a>(paths: impl IntoIterator<Item = &'a DevicePath>) -> String {
+    let paths: Vec<&DevicePath> = paths.into_iter().collect();
+
+    paths
+        .iter()
+        .find(|DevicePath(p)| p.parent() == Some(Path::new("/dev")))
+        .or_else(|| paths.first())
+        .map(|DevicePath(p)| file_name(p))
+        .unwrap_or_default()
+}
+
+/// Every mount target, comma separated, followed by configured targets that are not mounted.
+fn mount_target(xs: &Mounts, unmounted: &Mounts) -> Option<String> {
+    if xs.is_empty() && unmounted.is_empty() {
+        return None;
+    }
+
+    let xs: Vec<String> = xs
+        .iter()
+        .map(|Mount { target, .. }| target.0.to_string_lossy().to_string())
+        .chain(
+            unmounted
+                .iter()
+                .map(|Mount { target, .. }| format!("{} (not mounted)", target.0.display())),
+        )
+        .collect();
+
+    Some(xs.join(","))
+}
+
+/// The zpool a device belongs to.
+///
+/// A vdev is a parent of its pool in the graph, so this is the name of any zpool among its children.
+fn pool(d: &Device) -> Option<String> {
+    match d {
+        Device::Zpool(x) => Some(x.name.clone()),
+        Device::Dataset(x) => x.name.split('/').next().map(String::from),
+        d => {
+            let xs: Vec<&str> = children(d)
+                .into_iter()
+                .filter_map(|x| match x {
+                    Device::Zpool(x) => Some(x.name.as_str()),
+                    _ => None,
+                })
+                .collect();
+
+            if xs.is_empty() {
+                None
+            } else {
+                Some(xs.join(","))
+            }
+        }
+    }
+}
+
+fn row(d: &Device) -> Row {
+    let (name, kind, size, fs_type, mount, serial) = match d {
+        Device::Root(_) => ("/".into(), "root", None, None, None, None),
+        Device::ScsiDevice(x) => (
+            file_name(&x.devpath),
+            "disk",
+            Some(x.size),
+            x.filesystem_type.clone(),
+            mount_target(&x.mounts, &x.unmounted),
+            x.serial.clone(),
+        ),
+        Device::Partition(x) => (
+            file_name(&x.devpath),
+            "part",
+            Some(x.size),
+            x.filesystem_type.clone(),
+            mount_target(&x.mounts, &x.unmounted),
+            x.serial.clone(),
+        ),
+        Device::MdRaid(x) => (
+            dev_name(&x.paths),
+            "md",
+            Some(x.size),
+            x.filesystem_type.clone(),
+            mount_target(&x.mounts, &x.unmounted),
+            None,
+        ),
+        Device::Mpath(x) => (
+            x.dm_name.clone(),
+            "mpath",
+            Some(x.size),
+            x.filesystem_type.clone(),
+            mount_target(&x.mounts, &x.unmounted),
+            x.serial.clone(),
+        ),
+        Device::VolumeGroup(x) => (x.name.clone(), "vg", Some(x.size), None, None, None),
+        Device::LogicalVolume(x) => (
+            x.name.clone(),
+            "lvm",
+            Some(x.size),
+            x.filesystem_type.clone(),
+            mount_target(&x.mounts, &x.unmounted),
+            None,
+        ),
+        Device::Zpool(x) => (
+            x.name.clone(),
+            "zpool",
+            Some(x.size),
+            None,
+            mount_target(&x.mounts, &x.unmounted),
+            None,
+        ),
+        Device::Dataset(x) => (
+            x.name.clone(),
+            "zfs",
+            None,
+            Some("zfs".into()),
+            mount_target(&x.mounts, &x.unmounted),
+            None,
+        ),
+    };
+
+    vec![
+        name,
+        kind.into(),
+        size.map(human_size).unwrap_or_default(),
+        fs_type.unwrap_or_default(),
+        mount.unwrap_or_default(),
+        pool(d).unwrap_or_default(),
+        serial.unwrap_or_default(),
+    ]
+}
+
+pub fn children(d: &Device) -> Vec<&Device> {
+    match d {
+        Device::Root(x) => x.children.iter().collect(),
+        Device::ScsiDevice(x) => x.children.iter().collect(),
+        Device::Partition(x) => x.children.iter().collect(),
+        Device::MdRaid(x) => x.children.iter().collect(),
+        Device::Mpath(x) => x.children.iter().collect(),
+        Device::VolumeGroup(x) => x.children.iter().collect(),
+        Device::LogicalVolume(x) => x.children.iter().collect(),
+        Device::Zpool(x) => x.children.iter().collect(),
+        Device::Dataset(_) => vec![],
+    }
+}
+
+fn has_path(d: &Device, p: &DevicePath) -> bool {
+    match d {
+        Device::ScsiDevice(x) => x.paths.contains(p),
+        Device::Partition(x) => x.paths.contains(p),
+        Device::MdRaid(x) => x.paths.contains(p),
+        Device::Mpath(x) => x.paths.contains(p),
+        Device::LogicalVolume(x) => x.paths.contains(p),
+        Device::Root(_) | Device::VolumeGroup(_) | Device::Zpool(_) | Device::Dataset(_) => false,
+    }
+}
+
+/// Finds the first device in the graph known by the given path.
+pub fn find_by_path<'a>(d: &'a Device, p: &DevicePath) -> Option<&'a Device> {
+    if has_path(d, p) {
+        return Some(d);
+    }
+
+    children(d).into_iter().find_map(|x| find_by_path(x, p))
+}
+
+fn collect_rows(d: &Device, prefix: &str, last: bool, top: bool, rows: &mut Vec<Row>) {
+    let mut r = row(d);
+
+    let child_prefix = if top {
+        String::new()
+    } else {
+        r[0] = format!("{}{}{}", prefix, if last { "└─" } else { "├─" }, r[0]);
+
+        format!("{}{}", prefix, if last { "  " } else { "│ " })
+    };
+
+    rows.push(r);
+
+    let xs = children(d);
+
+    for (idx, x) in xs.iter().enumerate() {
+        collect_rows(x, &child_prefix, idx == xs.len() - 1, false, rows);
+    }
+}
+
+fn header(xs: &[&str]) -> Row {
+    xs.iter().map(|x| (*x).to_string()).collect()
+}
+
+fn client_row(x: &ClientMount) -> Row {
+    let (server, name) = match x {
+        ClientMount::Lustre(x) => (
+            x.mgs
+                .iter()
+                .map(|nids| nids.join(","))
+                .collect::<Vec<_>>()
+                .join(":"),
+            match &x.subdir {
+                Some(p) => format!("{}{}", x.fsname, p.display()),
+                None => x.fsname.clone(),
+            },
+        ),
+        ClientMount::Nfs(x) => (x.server.clone(), x.export.to_string_lossy().to_string()),
+        ClientMount::Other(x) => (String::new(), x.source.0.to_string_lossy().to_string()),
+    };
+
+    let m = x.mount();
+
+    vec![
+        m.target.0.to_string_lossy().to_string(),
+        m.fs_type.0.clone(),
+        server,
+        name,
+    ]
+}
+
+fn format_table(rows: Vec<Row>) -> String {
+    let mut widths = vec![];
+
+    for r in &rows {
+        widths.resize(widths.len().max(r.len()), 0);
+
+        for (idx, x) in r.iter().enumerate() {
+            widths[idx] = widths[idx].max(x.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|r| {
+            r.iter()
+                .enumerate()
+                .map(|(idx, x)| format!("{}{}", x, " ".repeat(widths[idx] - x.chars().count())))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+/// Renders a device graph as an `lsblk` style tree.
+///
+/// When given a `Root`, its children are rendered as top level devices,
+/// followed by any devices the daemon left out of the graph
+/// and any mounts not backed by a local device.
+pub fn render_tree(d: &Device) -> String {
+    let mut rows = vec![header(&HEADERS)];
+
+    match d {
+        Device::Root(_) => {
+            for x in children(d) {
+                collect_rows(x, "", true, true, &mut rows);
+            }
+        }
+        x => collect_rows(x, "", true, true, &mut rows),
+    }
+
+    let mut out = format_table(rows);
+
+    if let Device::Root(Root { diagnostics, .. }) = d {
+        if !diagnostics.is_empty() {
+            out += "\nLeft out of the graph:\n";
+        }
+
+        for x in diagnostics {
+            out += &format!("{}: {}\n", x.device, x.reason);
+        }
+    }
+
+    if let Device::Root(Root { client_mounts, .. }) = d {
+        if !client_mounts.is_empty() {
+            let rows = std::iter::once(header(&["TARGET", "FSTYPE", "SERVER", "NAME"]))
+                .chain(client_mounts.iter().map(client_row))
+                .collect();
+
+            out += "\nClient mounts:\n";
+            out += &format_table(rows);
+        }
+    }
+
+    out
+}
+
+/// Renders mounts as a table sorted by target.
+pub fn render_mounts(xs: &[Mount]) -> String {
+    let mut xs: Vec<&Mount> = xs.iter().collect();
+
+    xs.sort_by(|a, b| a.target.cmp(&b.target));
+
+    let rows = std::iter::once(header(&["TARGET", "SOURCE", "FSTYPE", "OPTIONS"]))
+        .chain(xs.into_iter().map(|m| {
+            vec![
+                m.target.0.to_string_lossy().to_string(),
+                m.source.0.to_string_lossy().to_string(),
+                m.fs_type.0.clone(),
+                m.opts.0.clone(),
+            ]
+        }))
+        .collect();
+
+    format_table(rows)
+}
+
+/// Renders a single `MonitorEvent` as a line.
+///
+/// Commands that did not change the daemon's state are marked with `=`.
+pub fn render_event(x: &MonitorEvent) -> String {
+    let t = x
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!(
+        "{}.{:03} {} {:?}",
+        t.as_secs(),
+        t.subsec_millis(),
+        if x.changed { "*" } else { "=" },
+        x.command
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::{
+        devices::{Dataset, Diagnostic, Partition, ScsiDevice, Zpool},
+        mount::{FsType, MountOpts, MountPoint},
+    };
+    use im::ordset;
+
+    fn graph() -> Device {
+        let part = Device::Partition(Partition {
+            id: "partition:3600140550e41a841db244a992c31e7df-part1".into(),
+            serial: Some("3600140550e41a841db244a992c31e7df".into()),
+            scsi80: None,
+            partition_number: 1,
+            size: 1_073_741_824,
+            major: "8".into(),
+            minor: "1".into(),
+            devpath:
+                "/devices/pci0000:00/0000:00:0d.0/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1"
+                    .into(),
+            filesystem_type: Some("ext4".into()),
+            fs_uuid: None,
+            fs_label: None,
+            paths: ordset!["/dev/sda1".into()],
+            mounts: ordset![Mount::new(
+                MountPoint("/mnt/part1".into()),
+                "/dev/sda1".into(),
+                FsType("ext4".into()),
+                MountOpts("rw".into()),
+            )],
+            usage: None,
+            unmounted: ordset![],
+            children: ordset![],
+        });
+
+        Device::Root(Root {
+            children: ordset![Device::ScsiDevice(ScsiDevice {
+                id: "scsi:3600140550e41a841db244a992c31e7df".into(),
+                serial: Some("3600140550e41a841db244a992c31e7df".into()),
+                scsi80: None,
+                major: "8".into(),
+                minor: "0".into(),
+                devpath:
+                    "/devices/pci0000:00/0000:00:0d.0/ata1/host0/target0:0:0/0:0:0:0/block/sda"
+                        .into(),
+                size: 21_474_836_480,
+                filesystem_type: None,
+                fs_uuid: None,
+                fs_label: None,
+                paths: ordset!["/dev/sda".into()],
+                mounts: ordset![],
+                usage: None,
+                unmounted: ordset![],
+                children: ordset![part],
+            })],
+            diagnostics: ordset![],
+            client_mounts: ordset![],
+        })
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(1_073_741_824), "1.0G");
+        assert_eq!(human_size(21_474_836_480), "20G");
+    }
+
+    #[test]
+    fn test_render_tree() {
+        assert_eq!(
+            render_tree(&graph()),
+            "NAME   TYPE SIZE FSTYPE MOUNTPOINT POOL SERIAL
+sda    disk 20G                         3600140550e41a841db244a992c31e7df
+└─sda1 part 1.0G ext4   /mnt/part1      3600140550e41a841db244a992c31e7df
+"
+        );
+    }
+
+    #[test]
+    fn test_render_pool() {
+        let dataset = Device::Dataset(Dataset {
+            id: "dataset:2".into(),
+            guid: 2,
+            name: "pool1/home".into(),
+            kind: "filesystem".into(),
+            props: vec![],
+            mounts: ordset![],
+            usage: None,
+            unmounted: ordset![],
+        });
+
+        let zpool = Device::Zpool(Zpool {
+            id: "zpool:1".into(),
+            guid: 1,
+            name: "pool1".into(),
+            health: "ONLINE".into(),
+            state: "ACTIVE".into(),
+            size: 1_073_741_824,
+            vdev: libzfs_types::VDev::Root {
+                children: vec![],
+                spares: vec![],
+                cache: vec![],
+            },
+            props: vec![],
+            children: ordset![dataset],
+            mounts: ordset![],
+            usage: None,
+            unmounted: ordset![],
+        });
+
+        let g = Device::ScsiDevice(ScsiDevice {
+            id: "scsi:35000c500a3c4d1e7".into(),
+            serial: Some("35000c500a3c4d1e7".into()),
+            scsi80: None,
+            major: "8".into(),
+            minor: "16".into(),
+            devpath: "/devices/pci0000:00/0000:00:0d.0/ata2/host1/target1:0:0/1:0:0:0/block/sdb"
+                .into(),
+            size: 1_073_741_824,
+            filesystem_type: Some("zfs_member".into()),
+            fs_uuid: None,
+            fs_label: None,
+            paths: ordset!["/dev/sdb".into()],
+            mounts: ordset![],
+            usage: None,
+            unmounted: ordset![],
+            children: ordset![zpool],
+        });
+
+        assert_eq!(
+            render_tree(&g),
+            "NAME           TYPE  SIZE FSTYPE     MOUNTPOINT POOL  SERIAL
+sdb            disk  1.0G zfs_member            pool1 35000c500a3c4d1e7
+└─pool1        zpool 1.0G                       pool1
+  └─pool1/home zfs        zfs                   pool1
+"
+        );
+    }
+
+    #[test]
+    fn test_render_diagnostics() {
+        let g = Device::Root(Root {
+            children: ordset![],
+            diagnostics: ordset![Diagnostic {
+                device: "/devices/virtual/block/dm-3".into(),
+                reason: "Expected dm_vg_name".into(),
+            }],
+            client_mounts: ordset![],
+        });
+
+        assert_eq!(
+            render_tree(&g),
+            "NAME TYPE SIZE FSTYPE MOUNTPOINT POOL SERIAL
+
+Left out of the graph:
+/devices/virtual/block/dm-3: Expected dm_vg_name
+"
+        );
+    }
+
+    #[test]
+    fn test_render_client_mounts() {
+        let mount = |target: &str, source: &str, fs_type: &str| {
+            Mount::new(
+                MountPoint(target.into()),
+                source.into(),
+                FsType(fs_type.into()),
+                MountOpts("rw".into()),
+            )
+        };
+
+        let xs = [
+            mount(
+                "/mnt/fs1",
+                "10.0.0.1@tcp,10.1.0.1@o2ib:10.0.0.2@tcp:/fs1",
+                "lustre",
+            ),
+            mount("/home", "nfs:/export/home", "nfs4"),
+            mount("/run", "tmpfs", "tmpfs"),
+        ];
+
+        let g = Device::Root(Root {
+            client_mounts: xs.iter().filter_map(ClientMount::from_mount).collect(),
+            ..Root::default()
+        });
+
+        assert_eq!(
+            render_tree(&g),
+            "NAME TYPE SIZE FSTYPE MOUNTPOINT POOL SERIAL
+
+Client mounts:
+TARGET   FSTYPE SERVER                                  NAME
+/mnt/fs1 lustre 10.0.0.1@tcp,10.1.0.1@o2ib:10.0.0.2@tcp fs1
+/home    nfs4   nfs                                     /export/home
+/run     tmpfs                                          tmpfs
+"
+        );
+    }
+
+    #[test]
+    fn test_mount_target() {
+        let mount = |target: &str| {
+            Mount::new(
+                MountPoint(target.into()),
+                "/dev/sda1".into(),
+                FsType("ext4".into()),
+                MountOpts("rw".into()),
+            )
+        };
+
+        assert_eq!(mount_target(&ordset![], &ordset![]), None);
+        assert_eq!(
+            mount_target(&ordset![mount("/mnt/a")], &ordset![mount("/mnt/b")]),
+            Some("/mnt/a,/mnt/b (not mounted)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_find_by_path() {
+        let g = graph();
+
+        let x = find_by_path(&g, &"/dev/sda1".into()).map(|x| row(x)[0].clone());
+
+        assert_eq!(x, Some("sda1".to_string()));
+        assert_eq!(find_by_path(&g, &"/dev/sdb".into()), None);
+    }
+
+    #[test]
+    fn test_render_event() {
+        let x = MonitorEvent {
+            time: SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_570_000_000_250),
+            changed: false,
+            command: device_types::Command::Stream,
+        };
+
+        assert_eq!(render_event(&x), "1570000000.250 = Stream");
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Blocking client for short lived processes that
+//! should not spin up a runtime to send one command.
+
+use crate::{encode, Result, SOCKET_PATH};
+use device_types::{mount::Mount, Command};
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(SOCKET_PATH)
+    }
+}
+
+impl Client {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Client { path: path.into() }
+    }
+
+    fn write_cmd(&self, cmd: &Command) -> Result<UnixStream> {
+        let mut conn = UnixStream::connect(&self.path)?;
+
+        conn.write_all(encode(cmd)?.as_bytes())?;
+
+        Ok(conn)
+    }
+
+    /// Sends a single `Command` to the daemon.
+    ///
+    /// The command is borrowed, so callers can still spool it if the send fails.
+    pub fn send(&self, cmd: &Command) -> Result<()> {
+        let conn = self.write_cmd(cmd)?;
+
+        conn.shutdown(Shutdown::Write)?;
+
+        Ok(())
+    }
+
+    /// Fetches the mounts the daemon currently knows about.
+    pub fn get_mounts(&self) -> Result<Vec<Mount>> {
+        let mut conn = self.write_cmd(&Command::GetMounts)?;
+
+        let mut buf = vec![];
+
+        conn.read_to_end(&mut buf)?;
+
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
+
+/// Sends a single `Command` to the daemon at `SOCKET_PATH`.
+pub fn send(cmd: &Command) -> Result<()> {
+    Client::default().send(cmd)
+}
+
+/// Fetches mounts from the daemon at `SOCKET_PATH`.
+pub fn get_mounts() -> Result<Vec<Mount>> {
+    Client::default().get_mounts()
+}
@@ -0,0 +1,424 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Clients for `device-scanner-daemon`.
+//!
+//! The async client is intended for long running consumers.
+//! Short lived processes (udev and zed hooks) should use the
+//! [`blocking`](blocking/index.html) client instead.
+
+pub mod blocking;
+pub mod spool;
+
+use device_types::{
+    devices::Device, graph::Graph, monitor::MonitorEvent, mount::Mount, stats::Stats, Command,
+};
+use futures::{stream, Future, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use std::{
+    cmp, error, fmt, io,
+    path::{Path, PathBuf},
+    result,
+    time::Duration,
+};
+use tokio::{
+    codec::{FramedRead, LinesCodec, LinesCodecError},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    timer::delay_for,
+};
+
+/// The socket `device-scanner-daemon` listens on.
+pub const SOCKET_PATH: &str = "/var/run/device-scanner.sock";
+
+/// How long to wait before the first reconnect.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest to wait between reconnects.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug, derive_more::From)]
+pub enum Error {
+    Io(io::Error),
+    SerdeJson(serde_json::Error),
+    LinesCodecError(LinesCodecError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::SerdeJson(ref err) => write!(f, "{}", err),
+            Error::LinesCodecError(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::SerdeJson(ref err) => Some(err),
+            Error::LinesCodecError(ref err) => Some(err),
+        }
+    }
+}
+
+/// Serializes a `Command` into the newline delimited form the daemon expects.
+pub fn encode(cmd: &Command) -> Result<String> {
+    Ok(serde_json::to_string(cmd)? + "\n")
+}
+
+/// Doubles `x`, up to `MAX_BACKOFF`.
+pub fn next_backoff(x: Duration) -> Duration {
+    cmp::min(x * 2, MAX_BACKOFF)
+}
+
+/// How long to wait before the next reconnect.
+///
+/// Callers `reset` it only once a connection has done useful work,
+/// so a daemon that accepts and then closes is not hammered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff(INITIAL_BACKOFF)
+    }
+}
+
+impl Backoff {
+    pub fn duration(self) -> Duration {
+        self.0
+    }
+
+    pub fn reset(&mut self) {
+        self.0 = INITIAL_BACKOFF;
+    }
+
+    /// Sleeps for the current backoff, then doubles it.
+    pub async fn wait(&mut self) {
+        delay_for(self.0).await;
+
+        self.0 = next_backoff(self.0);
+    }
+}
+
+/// Calls `connect` until it succeeds, waiting on `backoff` between attempts.
+///
+/// `path` is only used to log failures.
+pub async fn connect_with<F, Fut, T, E>(path: &Path, backoff: &mut Backoff, connect: F) -> T
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = result::Result<T, E>>,
+    E: fmt::Display,
+{
+    loop {
+        match connect().await {
+            Ok(x) => return x,
+            Err(e) => {
+                tracing::warn!(
+                    "Could not connect to {:?}: {}. Retrying in {:?}",
+                    path,
+                    e,
+                    backoff.duration()
+                );
+
+                backoff.wait().await;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(SOCKET_PATH)
+    }
+}
+
+impl Client {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Client { path: path.into() }
+    }
+
+    async fn write_cmd(&self, cmd: &Command) -> Result<UnixStream> {
+        let mut conn = UnixStream::connect(&self.path).await?;
+
+        conn.write_all(encode(cmd)?.as_bytes()).await?;
+
+        Ok(conn)
+    }
+
+    /// Sends a single `Command` to the daemon.
+    pub async fn send(&self, cmd: Command) -> Result<()> {
+        let conn = self.write_cmd(&cmd).await?;
+
+        conn.shutdown(std::net::Shutdown::Write)?;
+
+        Ok(())
+    }
+
+    /// Fetches the mounts the daemon currently knows about.
+    pub async fn get_mounts(&self) -> Result<Vec<Mount>> {
+        let mut conn = self.write_cmd(&Command::GetMounts).await?;
+
+        let mut buf = vec![];
+
+        conn.read_to_end(&mut buf).await?;
+
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    /// Fetches the daemon's counters.
+    pub async fn get_stats(&self) -> Result<Stats> {
+        let mut conn = self.write_cmd(&Command::GetStats).await?;
+
+        let mut buf = vec![];
+
+        conn.read_to_end(&mut buf).await?;
+
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    /// Reads the first line of a stream.
+    async fn get_first<T: DeserializeOwned>(&self, cmd: &Command) -> Result<T> {
+        let mut framed = connect_stream(&self.path, cmd).await?;
+
+        match framed.next().await {
+            Some(line) => Ok(serde_json::from_str(&line?)?),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before a device graph was received",
+            )
+            .into()),
+        }
+    }
+
+    /// Fetches the current device graph once.
+    pub async fn get_graph(&self) -> Result<Device> {
+        self.get_first(&Command::Stream).await
+    }
+
+    /// Fetches the current device graph once, as nodes and edges.
+    pub async fn get_node_graph(&self) -> Result<Graph> {
+        self.get_first(&Command::StreamGraph).await
+    }
+
+    /// Streams every command the daemon accepts, as it is applied.
+    ///
+    /// Unlike `stream`, this does not reconnect if the connection drops.
+    pub async fn monitor(&self) -> Result<impl Stream<Item = Result<MonitorEvent>>> {
+        let conn = self.write_cmd(&Command::Monitor).await?;
+
+        Ok(FramedRead::new(conn, LinesCodec::new()).map(|line| Ok(serde_json::from_str(&line?)?)))
+    }
+
+    /// Streams the device graph every time it changes.
+    ///
+    /// If the connection drops (i.e. the daemon restarts),
+    /// the stream reconnects with exponential backoff.
+    pub fn stream(&self) -> impl Stream<Item = Device> {
+        self.stream_of(Command::Stream)
+    }
+
+    /// Like `stream`, but each graph is sent as nodes and edges.
+    pub fn stream_graph(&self) -> impl Stream<Item = Graph> {
+        self.stream_of(Command::StreamGraph)
+    }
+
+    fn stream_of<T: DeserializeOwned>(&self, cmd: Command) -> impl Stream<Item = T> {
+        stream::unfold(
+            (self.path.clone(), cmd, None, Backoff::default()),
+            |(path, cmd, lines, backoff)| async move {
+                let mut lines: Option<FramedRead<UnixStream, LinesCodec>> = lines;
+                let mut backoff = backoff;
+
+                loop {
+                    let mut framed = match lines.take() {
+                        Some(x) => x,
+                        None => {
+                            connect_with(&path, &mut backoff, || connect_stream(&path, &cmd)).await
+                        }
+                    };
+
+                    match framed.next().await {
+                        Some(Ok(x)) => match serde_json::from_str::<T>(&x) {
+                            Ok(x) => {
+                                backoff.reset();
+
+                                return Some((x, (path, cmd, Some(framed), backoff)));
+                            }
+                            Err(e) => {
+                                tracing::warn!("Could not parse device graph: {}", e);
+
+                                lines = Some(framed);
+
+                                continue;
+                            }
+                        },
+                        Some(Err(e)) => {
+                            tracing::warn!("Error reading from {:?}: {}", path, e);
+                        }
+                        None => {
+                            tracing::debug!("Connection to {:?} closed", path);
+                        }
+                    }
+
+                    tracing::debug!("Reconnecting in {:?}", backoff.duration());
+
+                    backoff.wait().await;
+                }
+            },
+        )
+    }
+}
+
+async fn connect_stream(path: &Path, cmd: &Command) -> Result<FramedRead<UnixStream, LinesCodec>> {
+    let mut conn = UnixStream::connect(path).await?;
+
+    conn.write_all(encode(cmd)?.as_bytes()).await?;
+
+    Ok(FramedRead::new(conn, LinesCodec::new()))
+}
+
+/// Sends a single `Command` to the daemon at `SOCKET_PATH`.
+pub async fn send(cmd: Command) -> Result<()> {
+    Client::default().send(cmd).await
+}
+
+/// Fetches mounts from the daemon at `SOCKET_PATH`.
+pub async fn get_mounts() -> Result<Vec<Mount>> {
+    Client::default().get_mounts().await
+}
+
+/// Fetches counters from the daemon at `SOCKET_PATH`.
+pub async fn get_stats() -> Result<Stats> {
+    Client::default().get_stats().await
+}
+
+/// Fetches the current device graph from the daemon at `SOCKET_PATH`.
+pub async fn get_graph() -> Result<Device> {
+    Client::default().get_graph().await
+}
+
+/// Streams accepted commands from the daemon at `SOCKET_PATH`.
+pub async fn monitor() -> Result<impl Stream<Item = Result<MonitorEvent>>> {
+    Client::default().monitor().await
+}
+
+/// Streams device graphs from the daemon at `SOCKET_PATH`.
+pub fn stream() -> impl Stream<Item = Device> {
+    Client::default().stream()
+}
+
+/// Streams device graphs as nodes and edges from the daemon at `SOCKET_PATH`.
+pub fn stream_graph() -> impl Stream<Item = Graph> {
+    Client::default().stream_graph()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::devices::Root;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&Command::Stream).unwrap(), "\"Stream\"\n");
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        assert_eq!(next_backoff(INITIAL_BACKOFF), Duration::from_millis(200));
+        assert_eq!(next_backoff(Duration::from_secs(8)), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_connect_with() {
+        let attempts = std::cell::Cell::new(0);
+        let mut backoff = Backoff::default();
+
+        let x = connect_with(Path::new(SOCKET_PATH), &mut backoff, || {
+            attempts.set(attempts.get() + 1);
+
+            futures::future::ready(if attempts.get() < 3 {
+                Err("refused")
+            } else {
+                Ok(attempts.get())
+            })
+        })
+        .await;
+
+        assert_eq!(x, 3);
+        assert_eq!(backoff.duration(), Duration::from_millis(400));
+
+        backoff.reset();
+
+        assert_eq!(backoff, Backoff::default());
+    }
+
+    #[tokio::test]
+    async fn test_stream_reconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+
+            // Each connection gets a single graph and is then dropped.
+            while let Some(Ok(mut sock)) = incoming.next().await {
+                let root = serde_json::to_string(&Device::Root(Root::default())).unwrap();
+
+                sock.write_all((root + "\n").as_bytes()).await.unwrap();
+            }
+        });
+
+        let xs: Vec<Device> = Client::new(&path).stream().take(2).collect().await;
+
+        assert_eq!(
+            xs,
+            vec![Device::Root(Root::default()), Device::Root(Root::default())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_backs_off_when_closed() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use tokio::future::FutureExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let a = accepted.clone();
+
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+
+            // Each connection is dropped without sending anything.
+            while let Some(Ok(_)) = incoming.next().await {
+                a.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let mut xs = Box::pin(Client::new(&path).stream());
+
+        assert!(xs.next().timeout(Duration::from_millis(350)).await.is_err());
+
+        // Connects at 0ms, 100ms and 300ms.
+        assert!(accepted.load(Ordering::SeqCst) <= 4);
+    }
+}
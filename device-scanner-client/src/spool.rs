@@ -0,0 +1,215 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! A local queue for commands that could not be delivered.
+//!
+//! Hooks write a command here when the daemon socket is unreachable,
+//! and the daemon applies the queue in seqnum order when it starts,
+//! removing each file once its command has been applied.
+//! The queue lives under `/var/run`, as seqnums restart on reboot.
+//!
+//! Commands are applied without going through the daemon socket, so the
+//! daemon only trusts a spool it could have written itself: the directory
+//! must be mode 0700 and both it and each file owned by root, with no file
+//! writable by group or others.
+
+use crate::{encode, Result};
+use device_types::Command;
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The directory commands are spooled to.
+pub const SPOOL_PATH: &str = "/var/run/device-scanner/spool";
+
+/// The owner of a trusted spool.
+pub const ROOT_UID: u32 = 0;
+
+const EXT: &str = "json";
+
+/// Writes a command to the spool.
+///
+/// Files are named by seqnum, so they sort in the order they should be applied.
+/// The write is atomic, so a partially written command is never drained.
+pub fn write(dir: &Path, seqnum: i64, cmd: &Command) -> Result<PathBuf> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let name = format!("{:020}-{}", seqnum, process::id());
+
+    let tmp = dir.join(format!(".{}", name));
+    let path = dir.join(name).with_extension(EXT);
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?
+        .write_all(encode(cmd)?.as_bytes())?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(path)
+}
+
+fn untrusted(path: &Path, x: &fs::Metadata, owner: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "{:?} is owned by uid {} with mode {:o}, expected uid {}",
+            path,
+            x.uid(),
+            x.mode() & 0o7777,
+            owner
+        ),
+    )
+}
+
+/// Returns all spooled commands with their paths, in seqnum order.
+///
+/// The spool is only read if `dir` is owned by `owner` with mode 0700; the daemon
+/// passes `ROOT_UID`. Files are left in place, so a command is not lost if the daemon
+/// stops before applying it. Files that do not parse, are not owned by `owner`
+/// or are writable by group or others are logged and removed.
+pub fn pending(dir: &Path, owner: u32) -> Result<Vec<(PathBuf, Command)>> {
+    match fs::symlink_metadata(dir) {
+        Ok(ref x) if x.is_dir() && x.uid() == owner && x.mode() & 0o777 == 0o700 => {}
+        Ok(x) => return Err(untrusted(dir, &x, owner).into()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(xs) => xs
+            .map(|x| x.map(|x| x.path()))
+            .collect::<io::Result<Vec<_>>>()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    paths.retain(|p| p.extension().map(|x| x == EXT).unwrap_or(false));
+    paths.sort();
+
+    let mut xs = vec![];
+
+    for p in paths {
+        let meta = fs::symlink_metadata(&p)?;
+
+        if !meta.is_file() || meta.uid() != owner || meta.mode() & 0o022 != 0 {
+            tracing::warn!(
+                "Discarding untrusted spool file: {}",
+                untrusted(&p, &meta, owner)
+            );
+
+            fs::remove_file(&p)?;
+
+            continue;
+        }
+
+        match serde_json::from_slice(&fs::read(&p)?) {
+            Ok(x) => xs.push((p, x)),
+            Err(e) => {
+                tracing::warn!("Discarding unreadable spool file {:?}: {}", p, e);
+
+                fs::remove_file(&p)?;
+            }
+        }
+    }
+
+    Ok(xs)
+}
+
+/// Removes a spooled command once it has been applied.
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        x => Ok(x?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use test_support::mount_cmd;
+
+    /// A private directory to spool to, and its owner.
+    fn spool_dir() -> (tempfile::TempDir, u32) {
+        let tmp = tempfile::tempdir().unwrap();
+
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let uid = fs::metadata(tmp.path()).unwrap().uid();
+
+        (tmp, uid)
+    }
+
+    #[test]
+    fn test_pending_in_seqnum_order() {
+        let (tmp, uid) = spool_dir();
+        let dir = tmp.path();
+
+        assert_eq!(pending(dir, uid).unwrap(), vec![]);
+
+        let p2 = write(dir, 3549, &mount_cmd("/mnt/part2")).unwrap();
+        let p1 = write(dir, 998, &mount_cmd("/mnt/part1")).unwrap();
+        fs::write(dir.join("00000000000000000001-1.json"), "{").unwrap();
+
+        let expected = vec![
+            (p1.clone(), mount_cmd("/mnt/part1")),
+            (p2.clone(), mount_cmd("/mnt/part2")),
+        ];
+
+        assert_eq!(pending(dir, uid).unwrap(), expected);
+        assert_eq!(pending(dir, uid).unwrap(), expected);
+
+        remove(&p1).unwrap();
+        remove(&p1).unwrap();
+
+        assert_eq!(
+            pending(dir, uid).unwrap(),
+            vec![(p2.clone(), mount_cmd("/mnt/part2"))]
+        );
+
+        remove(&p2).unwrap();
+
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_pending_rejects_untrusted() {
+        let (tmp, uid) = spool_dir();
+        let dir = tmp.path();
+
+        let p1 = write(dir, 998, &mount_cmd("/mnt/part1")).unwrap();
+        let p2 = write(dir, 3549, &mount_cmd("/mnt/part2")).unwrap();
+
+        // The whole spool is refused if the directory belongs to someone else.
+        assert!(pending(dir, uid + 1).is_err());
+
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(pending(dir, uid).is_err());
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).unwrap();
+
+        fs::set_permissions(&p2, fs::Permissions::from_mode(0o620)).unwrap();
+
+        assert_eq!(
+            pending(dir, uid).unwrap(),
+            vec![(p1.clone(), mount_cmd("/mnt/part1"))]
+        );
+        assert!(!p2.exists());
+
+        // Changing the owner of a file needs root.
+        if uid == ROOT_UID {
+            let path = std::ffi::CString::new(p1.to_str().unwrap()).unwrap();
+
+            assert_eq!(unsafe { libc::chown(path.as_ptr(), 65534, 65534) }, 0);
+
+            assert_eq!(pending(dir, uid).unwrap(), vec![]);
+            assert!(!p1.exists());
+        }
+    }
+}
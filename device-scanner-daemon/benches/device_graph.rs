@@ -0,0 +1,115 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Benchmarks a full device graph rebuild on synthetic multipath setups.
+//!
+//! Every LUN is seen down two paths and carries one partition, which in turn
+//! backs an LV, is an MD member, or is a zpool vdev, so each LUN adds five
+//! devices or pools to the state and every bucket index is looked up.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use device_scanner_daemon::state::produce_device_graph;
+use device_types::{state::State, uevent::UEvent};
+use im::{ordset, vector};
+
+/// A disk with a WWN link, as the paths and maps of a LUN have.
+fn uevent(name: &str, major: u32, minor: u32) -> UEvent {
+    let mut x = test_support::uevent(name, &major.to_string(), &minor.to_string());
+
+    x.paths
+        .insert(format!("/dev/disk/by-id/wwn-{}", name).into());
+
+    x
+}
+
+/// The devices of a LUN, and the pool on it if there is one.
+fn lun(n: u32) -> (Vec<UEvent>, Option<libzfs_types::Pool>) {
+    let a = uevent(&format!("sd{}a", n), 8, n * 3);
+    let b = uevent(&format!("sd{}b", n), 8, n * 3 + 1);
+
+    let mpath = UEvent {
+        is_mpath: Some(true),
+        dm_name: Some(format!("mpath{}", n)),
+        dm_slave_mms: vector![
+            format!("{}:{}", a.major, a.minor),
+            format!("{}:{}", b.major, b.minor)
+        ],
+        ..uevent(&format!("dm-{}", n * 3), 253, n * 3)
+    };
+
+    let part = UEvent {
+        part_entry_mm: Some(format!("{}:{}", mpath.major, mpath.minor)),
+        part_entry_number: Some(1),
+        ..uevent(&format!("dm-{}", n * 3 + 1), 253, n * 3 + 1)
+    };
+
+    let top = uevent(&format!("dm-{}", n * 3 + 2), 253, n * 3 + 2);
+
+    match n % 3 {
+        0 => {
+            let lv = UEvent {
+                dm_slave_mms: vector![format!("{}:{}", part.major, part.minor)],
+                dm_vg_name: Some(format!("vg{}", n)),
+                dm_vg_size: Some(10_737_418_240),
+                vg_uuid: Some(format!("vg-uuid-{}", n)),
+                dm_lv_name: Some(format!("lv{}", n)),
+                lv_uuid: Some(format!("lv-uuid-{}", n)),
+                ..top
+            };
+
+            (vec![a, b, mpath, part, lv], None)
+        }
+        1 => {
+            let md = UEvent {
+                md_uuid: Some(format!("md-uuid-{}", n)),
+                md_devs: ordset![format!("/dev/dm-{}", n * 3 + 1).into()],
+                ..top
+            };
+
+            (vec![a, b, mpath, part, md], None)
+        }
+        _ => {
+            let pool = test_support::pool(
+                &format!("pool{}", n),
+                u64::from(n),
+                &[&format!("wwn-dm-{}", n * 3 + 1)],
+            );
+
+            (vec![a, b, mpath, part], Some(pool))
+        }
+    }
+}
+
+fn state(devices: u32) -> State {
+    let (uevents, pools): (Vec<_>, Vec<_>) = (0..devices / 5).map(lun).unzip();
+
+    State {
+        uevents: uevents
+            .into_iter()
+            .flatten()
+            .map(|x| (x.devpath.clone(), x))
+            .collect(),
+        zed_events: pools.into_iter().flatten().map(|x| (x.guid, x)).collect(),
+        ..State::new()
+    }
+}
+
+fn bench_produce_device_graph(c: &mut Criterion) {
+    let mut group = c.benchmark_group("produce_device_graph");
+
+    group.sample_size(10);
+
+    for n in &[5_000, 20_000] {
+        let state = state(*n);
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &state, |b, x| {
+            b.iter(|| produce_device_graph(x).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_produce_device_graph);
+criterion_main!(benches);
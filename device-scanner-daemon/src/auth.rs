@@ -0,0 +1,217 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Access control for commands received over the daemon socket.
+//!
+//! Read-only commands are open to any peer that can connect.
+//! Commands that mutate state are only accepted from root,
+//! or from a member of the group configured via `DEVICE_SCANNER_GROUP`.
+
+use device_types::Command;
+use libc::{gid_t, pid_t, uid_t};
+use std::{env, ffi::CString, fs, io, mem, os::unix::io::AsRawFd};
+
+/// Credentials of the process on the other end of a Unix socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCred {
+    pub pid: pid_t,
+    pub uid: uid_t,
+    pub gid: gid_t,
+}
+
+/// Reads `SO_PEERCRED` for the given socket.
+pub fn peer_cred<S: AsRawFd>(sock: &S) -> io::Result<PeerCred> {
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCred {
+        pid: ucred.pid,
+        uid: ucred.uid,
+        gid: ucred.gid,
+    })
+}
+
+/// Does this command change daemon state?
+pub fn is_mutating(cmd: &Command) -> bool {
+    match cmd {
+        Command::Stream
+        | Command::StreamGraph
+        | Command::GetMounts
+        | Command::Monitor
+        | Command::GetStats => false,
+        Command::PoolCommand(_)
+        | Command::UdevCommand(_)
+        | Command::MountCommand(_)
+        | Command::FstabCommand(_)
+        | Command::Reconcile(_)
+        | Command::Batch(_) => true,
+    }
+}
+
+/// Parses the supplementary groups out of the contents of `/proc/<pid>/status`.
+fn parse_groups(status: &str) -> Vec<gid_t> {
+    status
+        .lines()
+        .find(|l| l.starts_with("Groups:"))
+        .map(|l| {
+            l.trim_start_matches("Groups:")
+                .split_whitespace()
+                .filter_map(|x| x.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn supplementary_groups(pid: pid_t) -> io::Result<Vec<gid_t>> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+
+    Ok(parse_groups(&status))
+}
+
+/// Resolves a group name (or a numeric gid) to a gid.
+fn lookup_group(name: &str) -> io::Result<gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+
+    let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let grp = unsafe { libc::getgrnam(c_name.as_ptr()) };
+
+    if grp.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Group {} not found", name),
+        ));
+    }
+
+    Ok(unsafe { (*grp).gr_gid })
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    group: Option<gid_t>,
+}
+
+impl Policy {
+    pub fn new(group: Option<gid_t>) -> Self {
+        Policy { group }
+    }
+
+    /// Builds a policy from the `DEVICE_SCANNER_GROUP` env var.
+    /// If it is unset, only root may send mutating commands.
+    pub fn from_env() -> io::Result<Self> {
+        let group = match env::var("DEVICE_SCANNER_GROUP") {
+            Ok(x) => Some(lookup_group(&x)?),
+            Err(_) => None,
+        };
+
+        Ok(Policy::new(group))
+    }
+
+    fn is_privileged(&self, peer: &PeerCred, groups: impl FnOnce() -> Vec<gid_t>) -> bool {
+        if peer.uid == 0 {
+            return true;
+        }
+
+        match self.group {
+            Some(gid) => peer.gid == gid || groups().contains(&gid),
+            None => false,
+        }
+    }
+
+    /// Is the given peer allowed to send this command?
+    ///
+    /// A peer whose credentials could not be read is treated as unprivileged.
+    pub fn allows(&self, cmd: &Command, peer: Option<&PeerCred>) -> bool {
+        if !is_mutating(cmd) {
+            return true;
+        }
+
+        match peer {
+            Some(peer) => self.is_privileged(peer, || {
+                supplementary_groups(peer.pid).unwrap_or_else(|e| {
+                    tracing::warn!("Could not read groups for pid {}: {}", peer.pid, e);
+
+                    vec![]
+                })
+            }),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::{mount, zed::PoolCommand};
+
+    fn peer(uid: uid_t, gid: gid_t) -> PeerCred {
+        PeerCred { pid: 1, uid, gid }
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let status =
+            "Name:\tbash\nUid:\t1000\t1000\t1000\t1000\nGroups:\t10 190 1000 \nVmPeak:\t0 kB\n";
+
+        assert_eq!(parse_groups(status), vec![10, 190, 1000]);
+        assert_eq!(parse_groups("Name:\tbash\n"), Vec::<gid_t>::new());
+    }
+
+    #[test]
+    fn test_read_only_commands_are_open() {
+        let policy = Policy::default();
+
+        assert!(policy.allows(&Command::Stream, None));
+        assert!(policy.allows(&Command::StreamGraph, None));
+        assert!(policy.allows(&Command::GetMounts, Some(&peer(1000, 1000))));
+        assert!(policy.allows(&Command::Monitor, Some(&peer(1000, 1000))));
+    }
+
+    #[test]
+    fn test_mutating_commands_require_privilege() {
+        let cmd = Command::PoolCommand(PoolCommand::AddPools(vec![]));
+
+        let policy = Policy::default();
+
+        assert!(policy.allows(&cmd, Some(&peer(0, 0))));
+        assert!(!policy.allows(&cmd, None));
+
+        let policy = Policy::new(Some(190));
+
+        assert!(policy.is_privileged(&peer(1000, 190), Vec::new));
+        assert!(policy.is_privileged(&peer(1000, 1000), || vec![10, 190]));
+        assert!(!policy.is_privileged(&peer(1000, 1000), || vec![10]));
+
+        let cmd = Command::MountCommand(mount::MountCommand::AddMount(
+            mount::MountPoint("/mnt".into()),
+            "/dev/sda".into(),
+            mount::FsType("ext4".to_string()),
+            mount::MountOpts("rw".to_string()),
+            None,
+        ));
+
+        assert!(!Policy::default().allows(&cmd, Some(&peer(1000, 190))));
+    }
+}
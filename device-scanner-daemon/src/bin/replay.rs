@@ -0,0 +1,128 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! `device-scanner-replay` -- feeds a recorded command journal through the
+//! reducers offline and prints (or diffs) the resulting device graph.
+
+use device_scanner_daemon::{
+    journal::{self, Entry, Event},
+    state,
+};
+use device_types::state::State;
+use difference::{Changeset, Difference};
+use std::{
+    fs::File,
+    io::BufReader,
+    iter,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "device-scanner-replay",
+    about = "Replay a device-scanner command journal"
+)]
+struct Opts {
+    /// List journal entries instead of printing a graph
+    #[structopt(long)]
+    list: bool,
+
+    /// Only apply the first N entries
+    #[structopt(long)]
+    at: Option<usize>,
+
+    /// Diff the graphs produced after applying the first A and first B entries
+    #[structopt(long, number_of_values = 2, value_names = &["A", "B"])]
+    diff: Option<Vec<usize>>,
+
+    /// Path to the journal
+    #[structopt(parse(from_os_str))]
+    journal: PathBuf,
+}
+
+fn render(state: &State) -> Result<String, Box<dyn std::error::Error>> {
+    let output = state::produce_device_graph(state)?;
+
+    let v: serde_json::Value = serde_json::from_slice(&output)?;
+
+    Ok(serde_json::to_string_pretty(&v)?)
+}
+
+/// Renders the graph after applying the first `n` entries, for each `n` in `ns`.
+fn graphs_at(xs: Vec<Entry>, ns: &[usize]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let ns: Vec<usize> = ns.iter().map(|n| (*n).min(xs.len())).collect();
+    let last = ns.iter().cloned().max().unwrap_or(0);
+
+    let mut out = vec![String::new(); ns.len()];
+    let mut state = State::new();
+
+    let xs = iter::once(None).chain(xs.into_iter().map(Some));
+
+    for (count, x) in xs.enumerate().take(last + 1) {
+        if let Some(x) = x {
+            state = journal::apply(&state, x)
+                .map_err(|e| format!("Could not apply entry {}: {}", count, e))?;
+        }
+
+        for (idx, n) in ns.iter().enumerate() {
+            if *n == count {
+                out[idx] = render(&state)?;
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn secs(x: SystemTime) -> f64 {
+    let d = x
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+
+    d.as_secs() as f64 + f64::from(d.subsec_millis()) / 1000.0
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts = Opts::from_args();
+
+    let xs = journal::read(BufReader::new(File::open(&opts.journal)?))?;
+
+    if opts.list {
+        for (idx, x) in xs.iter().enumerate() {
+            let pid = x.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".into());
+
+            match &x.event {
+                Event::Start => println!("{}\t{:.3}\t{}\tStart", idx + 1, secs(x.time), pid),
+                Event::Command(cmd) => {
+                    println!("{}\t{:.3}\t{}\t{:?}", idx + 1, secs(x.time), pid, cmd)
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    match opts.diff {
+        Some(ref ys) => {
+            let gs = graphs_at(xs, ys)?;
+
+            for d in Changeset::new(&gs[0], &gs[1], "\n").diffs {
+                match d {
+                    Difference::Same(x) => x.lines().for_each(|l| println!(" {}", l)),
+                    Difference::Rem(x) => x.lines().for_each(|l| println!("-{}", l)),
+                    Difference::Add(x) => x.lines().for_each(|l| println!("+{}", l)),
+                }
+            }
+        }
+        None => {
+            let n = opts.at.unwrap_or(xs.len());
+
+            println!("{}", graphs_at(xs, &[n])?[0]);
+        }
+    }
+
+    Ok(())
+}
@@ -3,15 +3,29 @@
 // license that can be found in the LICENSE file.
 
 use crate::{
+    auth::{self, Policy},
     error,
-    reducers::{mount::update_mount, udev::update_udev, zed::update_zed_events},
-    state,
+    journal::Journal,
+    reducers,
+    scanner::{self, Scan},
+    state, usage,
+};
+use device_scanner_client::spool;
+use device_types::{
+    devices::Device,
+    graph::Graph,
+    monitor::MonitorEvent,
+    mount::Mount,
+    state::{FsUsages, State},
+    stats::Stats,
+    Command,
 };
-use device_types::{state::State, Command};
 use futures::{
-    channel::mpsc::UnboundedReceiver, channel::mpsc::UnboundedSender, future::join_all, StreamExt,
-    TryStreamExt,
+    channel::mpsc::UnboundedReceiver, channel::mpsc::UnboundedSender, channel::oneshot,
+    future::join_all, StreamExt, TryStreamExt,
 };
+use im::HashSet;
+use std::{path::PathBuf, time::SystemTime};
 use tokio::{
     codec::{FramedRead, LinesCodec},
     io::AsyncWriteExt,
@@ -20,7 +34,13 @@ use tokio::{
 
 pub enum WriterCmd {
     Add(UnixStream),
-    Msg(bytes::Bytes),
+    /// Subscribes a client to the device graph as a `Graph`.
+    AddGraph(UnixStream),
+    /// Sends the device tree to every subscriber, in the format each asked for.
+    Msg(Box<Device>),
+    /// Subscribes a client to `MonitorEvent`s.
+    AddMonitor(UnixStream),
+    Event(bytes::Bytes),
 }
 
 fn is_error(xs: &[Result<(), std::io::Error>], idx: usize) -> bool {
@@ -33,95 +53,607 @@ fn is_error(xs: &[Result<(), std::io::Error>], idx: usize) -> bool {
     }
 }
 
+/// Writes `x` to every writer, dropping those that error.
+async fn write_to_all(mut writers: Vec<UnixStream>, x: &[u8]) -> Vec<UnixStream> {
+    tracing::trace!("Starting write to all clients");
+
+    let xs = join_all(writers.iter_mut().map(|writer| writer.write_all(x))).await;
+
+    let writers: Vec<_> = writers
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| is_error(&xs, *idx))
+        .map(|(_, w)| w)
+        .collect();
+
+    tracing::trace!("{} clients remain.", writers.len());
+
+    writers
+}
+
+/// Encodes `x` for writers, unless there are none.
+fn encode_for<T: serde::Serialize>(writers: &[UnixStream], x: &T) -> Option<bytes::Bytes> {
+    if writers.is_empty() {
+        return None;
+    }
+
+    state::encode(x)
+        .map_err(|e| tracing::warn!("Could not encode device graph: {}", e))
+        .ok()
+}
+
 pub async fn writer(mut rx: UnboundedReceiver<WriterCmd>) {
     let mut writers = vec![];
+    let mut graph_writers = vec![];
+    let mut monitors = vec![];
 
     while let Some(cmd) = rx.next().await {
         match cmd {
             WriterCmd::Add(w) => writers.push(w),
+            WriterCmd::AddGraph(w) => graph_writers.push(w),
             WriterCmd::Msg(x) => {
-                tracing::trace!("Starting write to all clients");
+                if let Some(b) = encode_for(&writers, &x) {
+                    writers = write_to_all(writers, &b).await;
+                }
+
+                if let Some(b) = encode_for(&graph_writers, &Graph::from(x.as_ref())) {
+                    graph_writers = write_to_all(graph_writers, &b).await;
+                }
+            }
+            WriterCmd::AddMonitor(w) => monitors.push(w),
+            WriterCmd::Event(x) => monitors = write_to_all(monitors, &x).await,
+        }
+    }
+}
 
-                let xs = join_all(writers.iter_mut().map(|writer| writer.write_all(&x))).await;
+/// Commands sent from connection handlers to the `state_loop`.
+pub enum StateCmd {
+    /// Applies a mutating `Command` to `State`.
+    Update {
+        time: SystemTime,
+        pid: Option<i32>,
+        cmd: Command,
+    },
+    /// Applies a `Command` a hook spooled while the daemon was down,
+    /// then removes it from the spool.
+    Spooled(PathBuf, Command),
+    /// Answers a read-only `Command` on the given socket.
+    Query(Command, UnixStream),
+    /// Brings `State` in line with a `Scan`, taken by the daemon or by `uevent-listener`.
+    Reconcile(Scan),
+    /// Answers with the local mounts to sample, so their usage can be sampled outside the loop.
+    GetSampledMounts(oneshot::Sender<HashSet<Mount>>),
+    /// Replaces the sampled filesystem usage of local mounts.
+    SetUsage(FsUsages),
+}
 
-                writers = writers
-                    .into_iter()
-                    .enumerate()
-                    .filter(|(idx, _)| is_error(&xs, *idx))
-                    .map(|(_, w)| w)
-                    .collect();
+/// Reads commands from a single connection until it closes.
+///
+/// Mutating commands can be sent over the same connection back to back.
+/// A read-only command hands the socket to the `state_loop` and ends the read.
+async fn handle_connection(
+    sock: UnixStream,
+    tx: UnboundedSender<StateCmd>,
+    policy: Policy,
+) -> Result<(), error::Error> {
+    let peer = auth::peer_cred(&sock)
+        .map_err(|e| tracing::warn!("Could not read peer credentials: {}", e))
+        .ok();
 
-                tracing::trace!("{} clients remain.", writers.len());
+    let mut framed = FramedRead::new(sock, LinesCodec::new());
+
+    while let Some(x) = framed.next().await {
+        let time = SystemTime::now();
+
+        let cmd = serde_json::from_str::<Command>(x?.trim_end())?;
+
+        tracing::debug!("Incoming Command: {:?}", cmd);
+
+        if !policy.allows(&cmd, peer.as_ref()) {
+            tracing::warn!("Rejecting Command from unprivileged peer {:?}", peer);
+
+            framed.into_inner().shutdown(std::net::Shutdown::Both)?;
+
+            return Ok(());
+        }
+
+        match cmd {
+            Command::Reconcile(x) => tx.unbounded_send(StateCmd::Reconcile(x))?,
+            cmd if auth::is_mutating(&cmd) => tx.unbounded_send(StateCmd::Update {
+                time,
+                pid: peer.map(|x| x.pid),
+                cmd,
+            })?,
+            cmd => {
+                tx.unbounded_send(StateCmd::Query(cmd, framed.into_inner()))?;
+
+                return Ok(());
             }
         }
     }
+
+    Ok(())
 }
 
+/// Accepts connections, handling each one in its own task.
 pub async fn reader(
     listener: UnixListener,
-    tx: UnboundedSender<WriterCmd>,
+    tx: UnboundedSender<StateCmd>,
+    policy: Policy,
 ) -> Result<(), error::Error> {
     let mut listener = listener
         .incoming()
         .inspect_ok(|_| tracing::debug!("Client connected"));
 
-    let mut state = State::new();
-
     while let Some(sock) = listener.try_next().await? {
-        let (x, sock) = FramedRead::new(sock, LinesCodec::new()).into_future().await;
+        let fut = handle_connection(sock, tx.clone(), policy.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                tracing::warn!("Error handling connection: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn query(
+    state: &State,
+    stats: &Stats,
+    cmd: Command,
+    mut sock: UnixStream,
+    tx: &UnboundedSender<WriterCmd>,
+) -> Result<(), error::Error> {
+    match cmd {
+        Command::Stream => {
+            let output = state::produce_device_graph(state)?;
+
+            sock.write_all(&output).await?;
 
-        let mut sock = sock.into_inner();
+            tx.unbounded_send(WriterCmd::Add(sock))?;
+        }
+        Command::StreamGraph => {
+            let output = state::encode(&Graph::from(&state::device_tree(state)))?;
 
-        if let Some(x) = x {
-            let cmd = serde_json::from_str::<Command>(x?.trim_end())?;
+            sock.write_all(&output).await?;
 
-            tracing::debug!("Incoming Command: {:?}", cmd);
+            tx.unbounded_send(WriterCmd::AddGraph(sock))?;
+        }
+        Command::GetMounts => {
+            let v = serde_json::to_string(&state.local_mounts)?;
+            let b = bytes::BytesMut::from(v + "\n");
+            let b = b.freeze();
 
-            match cmd {
-                Command::Stream => {
-                    let output = state::produce_device_graph(&state)?;
+            sock.shutdown(std::net::Shutdown::Read)?;
 
-                    sock.write_all(&output).await?;
+            sock.write_all(&b).await?;
+        }
+        Command::GetStats => {
+            let v = serde_json::to_string(stats)?;
 
-                    tx.unbounded_send(WriterCmd::Add(sock))?;
+            sock.shutdown(std::net::Shutdown::Read)?;
 
-                    continue;
+            sock.write_all((v + "\n").as_bytes()).await?;
+        }
+        Command::Monitor => {
+            sock.shutdown(std::net::Shutdown::Read)?;
+
+            tx.unbounded_send(WriterCmd::AddMonitor(sock))?;
+        }
+        Command::UdevCommand(_)
+        | Command::MountCommand(_)
+        | Command::PoolCommand(_)
+        | Command::FstabCommand(_)
+        | Command::Reconcile(_)
+        | Command::Batch(_) => {
+            tracing::warn!("Ignoring mutating Command sent as a query: {:?}", cmd);
+        }
+    };
+
+    Ok(())
+}
+
+/// Applies a mutating `Command`, recording it and notifying writers.
+///
+/// Also returns whether the command changed `State`.
+fn apply(
+    state: &State,
+    stats: &mut Stats,
+    time: SystemTime,
+    pid: Option<i32>,
+    cmd: Command,
+    tx: &UnboundedSender<WriterCmd>,
+    journal: &mut Option<Journal>,
+) -> Result<(State, bool), error::Error> {
+    if let Some(journal) = journal.as_mut() {
+        if let Err(e) = journal.record(pid, &cmd) {
+            tracing::warn!("Could not write to journal: {}", e);
+        }
+    }
+
+    let next = reducers::update(state, cmd.clone(), stats)?;
+
+    let changed = &next != state;
+
+    let event = MonitorEvent {
+        time,
+        changed,
+        command: cmd,
+    };
+
+    tx.unbounded_send(WriterCmd::Event(
+        (serde_json::to_string(&event)? + "\n").into(),
+    ))?;
+
+    Ok((next, changed))
+}
+
+/// Owns `State`, applying updates and answering queries in the order they arrive.
+pub async fn state_loop(
+    mut rx: UnboundedReceiver<StateCmd>,
+    tx: UnboundedSender<WriterCmd>,
+    mut journal: Option<Journal>,
+) -> Result<(), error::Error> {
+    let mut state = State::new();
+    let mut stats = Stats::default();
+
+    while let Some(x) = rx.next().await {
+        match x {
+            StateCmd::Query(cmd, sock) => {
+                if let Err(e) = query(&state, &stats, cmd, sock, &tx).await {
+                    tracing::warn!("Error answering query: {}", e);
                 }
-                Command::GetMounts => {
-                    let v = serde_json::to_string(&state.local_mounts)?;
-                    let b = bytes::BytesMut::from(v + "\n");
-                    let b = b.freeze();
 
-                    sock.shutdown(std::net::Shutdown::Read)?;
+                continue;
+            }
+            StateCmd::Update { time, pid, cmd } => {
+                match apply(&state, &mut stats, time, pid, cmd, &tx, &mut journal) {
+                    Ok((next, true)) => state = next,
+                    // i.e. a stale uevent, which should not cause a rebuild.
+                    Ok((_, false)) => continue,
+                    Err(e) => {
+                        tracing::warn!("Discarding command: {}", e);
 
-                    sock.write_all(&b).await?;
+                        continue;
+                    }
+                }
+            }
+            StateCmd::Spooled(path, cmd) => {
+                let x = apply(
+                    &state,
+                    &mut stats,
+                    SystemTime::now(),
+                    None,
+                    cmd,
+                    &tx,
+                    &mut journal,
+                );
 
-                    continue;
+                // A command that cannot be applied now never will be, so it is removed either way.
+                if let Err(e) = spool::remove(&path) {
+                    tracing::warn!("Could not remove spool file {:?}: {}", path, e);
                 }
-                Command::UdevCommand(x) => {
-                    sock.shutdown(std::net::Shutdown::Both)?;
 
-                    state.uevents = update_udev(&state.uevents, x);
+                match x {
+                    Ok((next, true)) => state = next,
+                    Ok((_, false)) => continue,
+                    Err(e) => {
+                        tracing::warn!("Discarding spooled command {:?}: {}", path, e);
+
+                        continue;
+                    }
                 }
-                Command::MountCommand(x) => {
-                    sock.shutdown(std::net::Shutdown::Both)?;
+            }
+            StateCmd::Reconcile(scan) => {
+                let (cmds, drift) = scanner::reconcile(&state.uevents, scan);
+
+                if drift.is_empty() {
+                    tracing::debug!("State matches sysfs");
 
-                    state.local_mounts = update_mount(state.local_mounts, x);
+                    continue;
+                } else if state.uevents.is_empty() {
+                    tracing::info!("Bootstrapped {} devices from sysfs", drift.added.len());
+                } else {
+                    tracing::warn!("State drifted from sysfs: {}", drift);
                 }
-                Command::PoolCommand(x) => {
-                    sock.shutdown(std::net::Shutdown::Both)?;
 
-                    state.zed_events = update_zed_events(state.zed_events, x)?
+                for x in cmds {
+                    let cmd = Command::UdevCommand(x);
+
+                    match apply(
+                        &state,
+                        &mut stats,
+                        SystemTime::now(),
+                        None,
+                        cmd,
+                        &tx,
+                        &mut journal,
+                    ) {
+                        Ok((next, _)) => state = next,
+                        Err(e) => tracing::warn!("Discarding reconciled uevent: {}", e),
+                    }
+                }
+            }
+            StateCmd::GetSampledMounts(tx) => {
+                let _ = tx.send(usage::sampled_mounts(
+                    &state.local_mounts,
+                    &state::zfs_names(&state.zed_events),
+                ));
+
+                continue;
+            }
+            StateCmd::SetUsage(fs_usage) => {
+                if fs_usage == state.fs_usage {
+                    continue;
                 }
-            };
 
-            let output = state::produce_device_graph(&state)?;
+                state.fs_usage = fs_usage;
+            }
+        };
 
-            tx.unbounded_send(WriterCmd::Msg(output))?;
+        tx.unbounded_send(WriterCmd::Msg(Box::new(state::device_tree(&state))))?;
 
-            tracing::debug!("sent new output");
-        }
+        tracing::debug!("sent new output");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::{
+        mount::MountPoint,
+        udev::UdevCommand,
+        zed::{zpool, PoolCommand},
+    };
+    use futures::channel::mpsc;
+    use im::ordset;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use test_support::{mount_cmd, uevent};
+    use tokio::io::AsyncReadExt;
+
+    fn line(cmd: &Command) -> String {
+        serde_json::to_string(cmd).unwrap() + "\n"
+    }
+
+    #[tokio::test]
+    async fn test_persistent_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (tx, _rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        tokio::spawn(async move { state_loop(state_rx, tx, None).await.unwrap() });
+        tokio::spawn(async move { reader(listener, state_tx, Policy::default()).await.unwrap() });
+
+        let mut conn = UnixStream::connect(&path).await.unwrap();
+
+        for x in &["/mnt/part1", "/mnt/part2"] {
+            conn.write_all(line(&mount_cmd(x)).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        conn.write_all(line(&Command::GetMounts).as_bytes())
+            .await
+            .unwrap();
+
+        let mut buf = vec![];
+        conn.read_to_end(&mut buf).await.unwrap();
+
+        let xs: Vec<device_types::mount::Mount> = serde_json::from_slice(&buf).unwrap();
+
+        assert_eq!(xs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_batch_rebuilds_once() {
+        let (tx, rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        state_tx
+            .unbounded_send(StateCmd::Update {
+                time: SystemTime::now(),
+                pid: None,
+                cmd: Command::Batch(vec![mount_cmd("/mnt/part1"), mount_cmd("/mnt/part2")]),
+            })
+            .unwrap();
+
+        drop(state_tx);
+
+        state_loop(state_rx, tx, None).await.unwrap();
+
+        let xs: Vec<WriterCmd> = rx.collect().await;
+
+        assert_eq!(
+            xs.iter()
+                .filter_map(|x| match x {
+                    WriterCmd::Msg(x) => Some(x),
+                    _ => None,
+                })
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_command_does_not_stop_loop() {
+        let (tx, rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        for cmd in &[
+            Command::PoolCommand(PoolCommand::RemovePool(zpool::Guid::from(1))),
+            mount_cmd("/mnt/part1"),
+        ] {
+            state_tx
+                .unbounded_send(StateCmd::Update {
+                    time: SystemTime::now(),
+                    pid: None,
+                    cmd: cmd.clone(),
+                })
+                .unwrap();
+        }
+
+        drop(state_tx);
+
+        state_loop(state_rx, tx, None).await.unwrap();
+
+        let xs: Vec<WriterCmd> = rx.collect().await;
+
+        assert_eq!(
+            xs.iter()
+                .filter_map(|x| match x {
+                    WriterCmd::Msg(x) => Some(x),
+                    _ => None,
+                })
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_does_not_rebuild() {
+        let (tx, rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        for _ in 0..2 {
+            state_tx
+                .unbounded_send(StateCmd::Update {
+                    time: SystemTime::now(),
+                    pid: None,
+                    cmd: mount_cmd("/mnt/part1"),
+                })
+                .unwrap();
+        }
+
+        drop(state_tx);
+
+        state_loop(state_rx, tx, None).await.unwrap();
+
+        let xs: Vec<WriterCmd> = rx.collect().await;
+
+        let events: Vec<MonitorEvent> = xs
+            .iter()
+            .filter_map(|x| match x {
+                WriterCmd::Event(x) => Some(serde_json::from_slice(x).unwrap()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            events.iter().map(|x| x.changed).collect::<Vec<_>>(),
+            vec![true, false]
+        );
+
+        assert_eq!(
+            xs.iter()
+                .filter_map(|x| match x {
+                    WriterCmd::Msg(x) => Some(x),
+                    _ => None,
+                })
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spooled_removed_once_applied() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let uid = std::fs::metadata(dir).unwrap().uid();
+
+        let (tx, rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        for (seqnum, x) in &[(998, "/mnt/part1"), (3549, "/mnt/part2")] {
+            let cmd = mount_cmd(x);
+            let path = spool::write(dir, *seqnum, &cmd).unwrap();
+
+            state_tx
+                .unbounded_send(StateCmd::Spooled(path, cmd))
+                .unwrap();
+        }
+
+        assert_eq!(spool::pending(dir, uid).unwrap().len(), 2);
+
+        drop(state_tx);
+
+        state_loop(state_rx, tx, None).await.unwrap();
+
+        assert_eq!(spool::pending(dir, uid).unwrap(), vec![]);
+
+        let xs: Vec<WriterCmd> = rx.collect().await;
+
+        assert_eq!(
+            xs.iter()
+                .filter_map(|x| match x {
+                    WriterCmd::Event(x) => Some(x),
+                    _ => None,
+                })
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_graph() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (tx, rx) = mpsc::unbounded();
+        let (state_tx, state_rx) = mpsc::unbounded();
+
+        tokio::spawn(writer(rx));
+        tokio::spawn(async move { state_loop(state_rx, tx, None).await.unwrap() });
+        tokio::spawn(async move { reader(listener, state_tx, Policy::default()).await.unwrap() });
+
+        let mut conn = UnixStream::connect(&path).await.unwrap();
+
+        let add = Command::UdevCommand(UdevCommand::Add(uevent("sde1", "8", "65")));
+
+        // Sent on the same connection, so the device is known before the graph is.
+        conn.write_all(line(&add).as_bytes()).await.unwrap();
+
+        conn.write_all(line(&Command::StreamGraph).as_bytes())
+            .await
+            .unwrap();
+
+        let mut lines = FramedRead::new(conn, LinesCodec::new());
+
+        let g: Graph = serde_json::from_str(&lines.next().await.unwrap().unwrap()).unwrap();
+
+        let mounts = |g: &Graph| {
+            g.nodes
+                .values()
+                .find_map(|x| match x {
+                    Device::ScsiDevice(x) => Some(x.mounts.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        assert_eq!(mounts(&g), ordset![]);
+
+        // Updates are sent to graph subscribers as they are applied.
+        let mut conn = UnixStream::connect(&path).await.unwrap();
+
+        conn.write_all(line(&mount_cmd("/mnt/part1")).as_bytes())
+            .await
+            .unwrap();
+
+        let g: Graph = serde_json::from_str(&lines.next().await.unwrap().unwrap()).unwrap();
+
+        assert_eq!(
+            mounts(&g).into_iter().map(|x| x.target).collect::<Vec<_>>(),
+            vec![MountPoint("/mnt/part1".into())]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Optional append-only journal of the commands applied to `State`.
+//!
+//! Each line is a JSON serialized `Entry`. A journal can be fed back through
+//! the reducers with `device-scanner-replay` to reconstruct how the daemon
+//! arrived at a given device graph.
+
+use crate::{
+    error::{self, Result},
+    reducers,
+};
+use device_types::{state::State, stats::Stats, Command};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Event<C = Command> {
+    /// The daemon started with empty `State`.
+    Start,
+    Command(C),
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Entry<C = Command> {
+    pub time: SystemTime,
+    /// The pid of the peer that sent the command, if known.
+    pub pid: Option<i32>,
+    pub event: Event<C>,
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path` for appending.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Journal { file })
+    }
+
+    fn write<C: serde::Serialize>(&mut self, entry: &Entry<C>) -> Result<()> {
+        let x = serde_json::to_string(entry)? + "\n";
+
+        self.file.write_all(x.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Records that the daemon (re)started, discarding any previous `State`.
+    pub fn record_start(&mut self) -> Result<()> {
+        self.write::<&Command>(&Entry {
+            time: SystemTime::now(),
+            pid: None,
+            event: Event::Start,
+        })
+    }
+
+    pub fn record(&mut self, pid: Option<i32>, cmd: &Command) -> Result<()> {
+        self.write(&Entry {
+            time: SystemTime::now(),
+            pid,
+            event: Event::Command(cmd),
+        })
+    }
+}
+
+/// Reads all entries from a journal.
+pub fn read(r: impl BufRead) -> Result<Vec<Entry>> {
+    r.lines()
+        .filter(|x| match x {
+            Ok(x) => !x.trim().is_empty(),
+            Err(_) => true,
+        })
+        .map(|x| Ok(serde_json::from_str(&x?)?))
+        .collect()
+}
+
+/// Applies a single entry to `State`.
+pub fn apply(state: &State, x: Entry) -> Result<State> {
+    match x.event {
+        Event::Start => Ok(State::new()),
+        Event::Command(cmd) => reducers::update(state, cmd, &mut Stats::default()),
+    }
+}
+
+/// Feeds entries through the reducers, producing the resulting `State`.
+pub fn replay(xs: impl IntoIterator<Item = Entry>) -> Result<State> {
+    xs.into_iter()
+        .enumerate()
+        .try_fold(State::new(), |state, (idx, x)| {
+            apply(&state, x)
+                .map_err(|e| error::none_error(format!("Could not apply entry {}: {}", idx + 1, e)))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::mount_cmd;
+
+    fn entry(event: Event) -> Entry {
+        Entry {
+            time: SystemTime::UNIX_EPOCH,
+            pid: Some(1),
+            event,
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let x = serde_json::to_string(&entry(Event::Command(mount_cmd("/mnt/part1")))).unwrap();
+
+        let xs = read(format!("{}\n\n{}\n", x, x).as_bytes()).unwrap();
+
+        assert_eq!(xs.len(), 2);
+        assert_eq!(xs[0], entry(Event::Command(mount_cmd("/mnt/part1"))));
+    }
+
+    #[test]
+    fn test_replay() {
+        let state = replay(vec![
+            entry(Event::Command(mount_cmd("/mnt/part1"))),
+            entry(Event::Command(mount_cmd("/mnt/part2"))),
+        ])
+        .unwrap();
+
+        assert_eq!(state.local_mounts.len(), 2);
+
+        let state = replay(vec![
+            entry(Event::Command(mount_cmd("/mnt/part1"))),
+            entry(Event::Start),
+            entry(Event::Command(mount_cmd("/mnt/part2"))),
+        ])
+        .unwrap();
+
+        assert_eq!(state.local_mounts.len(), 1);
+    }
+}
@@ -2,7 +2,11 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
+pub mod auth;
 pub mod daemon;
 pub mod error;
+pub mod journal;
 pub mod reducers;
+pub mod scanner;
 pub mod state;
+pub mod usage;
@@ -2,15 +2,32 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use device_scanner_daemon::daemon;
+use device_scanner_client::spool::{self, SPOOL_PATH};
+use device_scanner_daemon::{
+    auth::Policy,
+    daemon,
+    journal::Journal,
+    scanner::{self, Scanner},
+    usage,
+};
 use futures::channel::mpsc;
 use std::{
     convert::TryFrom,
     os::unix::{io::FromRawFd, net::UnixListener as NetUnixListener},
+    path::Path,
+    time::Duration,
 };
 use tokio::net::UnixListener;
 use tracing_subscriber::{fmt::Subscriber, EnvFilter};
 
+/// How often `State` is reconciled with sysfs, unless overridden by
+/// `DEVICE_SCANNER_RECONCILE_INTERVAL` (in seconds, 0 disables it).
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often filesystem usage is sampled, unless overridden by
+/// `DEVICE_SCANNER_USAGE_INTERVAL` (in seconds, 0 disables it).
+const USAGE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let subscriber = Subscriber::builder()
@@ -25,11 +42,90 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let listener = UnixListener::try_from(addr)?;
 
+    let policy = Policy::from_env()?;
+
+    let journal = match std::env::var("DEVICE_SCANNER_JOURNAL") {
+        Ok(path) => {
+            tracing::info!("Recording commands to {}", path);
+
+            let mut journal = Journal::open(path)?;
+
+            journal.record_start()?;
+
+            Some(journal)
+        }
+        Err(_) => None,
+    };
+
     let (tx, rx) = mpsc::unbounded();
 
     tokio::spawn(daemon::writer(rx));
 
-    daemon::reader(listener, tx).await?;
+    let (state_tx, state_rx) = mpsc::unbounded();
+
+    // Apply anything hooks spooled while we were down, before taking new commands.
+    let spooled = spool::pending(Path::new(SPOOL_PATH), spool::ROOT_UID).unwrap_or_else(|e| {
+        tracing::warn!("Could not read spool {}: {}", SPOOL_PATH, e);
+
+        vec![]
+    });
+
+    if !spooled.is_empty() {
+        tracing::info!("Applying {} spooled commands", spooled.len());
+    }
+
+    for (path, cmd) in spooled {
+        state_tx.unbounded_send(daemon::StateCmd::Spooled(path, cmd))?;
+    }
+
+    // Then bootstrap from sysfs, so devices that were set up before we started are known.
+    // Spooled uevents are applied first, so this scan does not make them look stale.
+    let scanner = Scanner::default();
+
+    match scanner::scan(scanner.clone()).await {
+        Ok(x) => state_tx.unbounded_send(daemon::StateCmd::Reconcile(x))?,
+        Err(e) => tracing::warn!("Could not scan sysfs: {}", e),
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = daemon::state_loop(state_rx, tx, journal).await {
+            tracing::error!("State loop exited: {}", e);
+
+            std::process::exit(1);
+        }
+    });
+
+    let interval = match std::env::var("DEVICE_SCANNER_RECONCILE_INTERVAL") {
+        Ok(x) => Duration::from_secs(x.parse()?),
+        Err(_) => RECONCILE_INTERVAL,
+    };
+
+    if interval > Duration::from_secs(0) {
+        let fut = scanner::reconcile_loop(scanner, interval, state_tx.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                tracing::error!("Reconcile loop exited: {}", e);
+            }
+        });
+    }
+
+    let interval = match std::env::var("DEVICE_SCANNER_USAGE_INTERVAL") {
+        Ok(x) => Duration::from_secs(x.parse()?),
+        Err(_) => USAGE_INTERVAL,
+    };
+
+    if interval > Duration::from_secs(0) {
+        let fut = usage::usage_loop(interval, state_tx.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                tracing::error!("Usage loop exited: {}", e);
+            }
+        });
+    }
+
+    daemon::reader(listener, state_tx, policy).await?;
 
     Ok(())
 }
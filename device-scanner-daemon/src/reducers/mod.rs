@@ -5,3 +5,87 @@
 pub mod mount;
 pub mod udev;
 pub mod zed;
+
+use crate::{error::Result, scanner};
+use device_types::{state::State, stats::Stats, Command};
+
+/// Produces a new `State` by applying a `Command` to the given one.
+///
+/// Read-only commands return an unchanged copy of the given `State`.
+/// Commands that are discarded (i.e. stale uevents) are counted in `stats`.
+pub fn update(state: &State, cmd: Command, stats: &mut Stats) -> Result<State> {
+    let mut state = state.clone();
+
+    match cmd {
+        Command::UdevCommand(x) => match udev::update_udev(&state.uevents, &state.seqnums, x) {
+            Some((uevents, seqnums)) => {
+                state.uevents = uevents;
+                state.seqnums = seqnums;
+            }
+            None => stats.stale_uevents += 1,
+        },
+        Command::MountCommand(x) => state.local_mounts = mount::update_mount(state.local_mounts, x),
+        Command::FstabCommand(x) => state.configured_mounts = mount::update_fstab(x),
+        Command::Reconcile(x) => {
+            for x in scanner::reconcile(&state.uevents, x).0 {
+                state = update(&state, Command::UdevCommand(x), stats)?;
+            }
+        }
+        Command::Batch(xs) => {
+            // Counted separately, so a batch that fails is not counted at all.
+            let mut batch_stats = stats.clone();
+
+            for x in xs {
+                state = update(&state, x, &mut batch_stats)?;
+            }
+
+            *stats = batch_stats;
+        }
+        Command::PoolCommand(x) => state.zed_events = zed::update_zed_events(state.zed_events, x)?,
+        Command::Stream
+        | Command::StreamGraph
+        | Command::GetMounts
+        | Command::Monitor
+        | Command::GetStats => {}
+    };
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::zed::{zpool, PoolCommand};
+    use test_support::mount_cmd;
+
+    #[test]
+    fn test_batch() {
+        let state = State::new();
+
+        let next = update(
+            &state,
+            Command::Batch(vec![
+                mount_cmd("/mnt/part1"),
+                Command::GetMounts,
+                Command::Batch(vec![mount_cmd("/mnt/part2")]),
+            ]),
+            &mut Stats::default(),
+        )
+        .unwrap();
+
+        assert_eq!(next.local_mounts.len(), 2);
+
+        // A failing command rejects the whole batch.
+        let x = update(
+            &next,
+            Command::Batch(vec![
+                mount_cmd("/mnt/part3"),
+                Command::PoolCommand(PoolCommand::RemovePool(zpool::Guid::from(1))),
+            ]),
+            &mut Stats::default(),
+        );
+
+        assert!(x.is_err());
+        assert_eq!(next.local_mounts.len(), 2);
+    }
+}
@@ -2,35 +2,64 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use device_types::mount::{Mount, MountCommand};
+use device_types::mount::{FstabCommand, Mount, MountCommand};
 use im::HashSet;
+use std::hash::BuildHasher;
+
+/// Removes every mount that `is` the given one.
+fn without<S: BuildHasher + Default>(
+    local_mounts: HashSet<Mount, S>,
+    mount: &Mount,
+) -> HashSet<Mount, S> {
+    local_mounts.into_iter().filter(|x| !x.is(mount)).collect()
+}
 
 /// Mutably updates the Mount portion of the device map in response to `MountCommand`s.
-pub fn update_mount<S: ::std::hash::BuildHasher>(
-    mut local_mounts: HashSet<Mount, S>,
+pub fn update_mount<S: BuildHasher + Default>(
+    local_mounts: HashSet<Mount, S>,
     cmd: MountCommand,
 ) -> HashSet<Mount, S> {
     match cmd {
-        MountCommand::AddMount(target, source, fstype, opts) => {
-            local_mounts.update(Mount::new(target, source, fstype, opts))
+        MountCommand::AddMount(target, source, fstype, opts, info) => {
+            let mount = Mount {
+                info,
+                ..Mount::new(target, source, fstype, opts)
+            };
+
+            // A reused `mount_id` means the old mount is gone.
+            without(local_mounts, &mount).update(mount)
         }
-        MountCommand::RemoveMount(target, source, fstype, opts) => {
-            local_mounts.without(&Mount::new(target, source, fstype, opts))
+        MountCommand::RemoveMount(target, source, fstype, opts, info) => without(
+            local_mounts,
+            &Mount {
+                info,
+                ..Mount::new(target, source, fstype, opts)
+            },
+        ),
+        MountCommand::ReplaceMount(target, source, fstype, opts, old_ops, info) => {
+            let mount = Mount {
+                info,
+                ..Mount::new(target, source, fstype, old_ops)
+            };
+
+            without(local_mounts, &mount).update(Mount { opts, ..mount })
         }
-        MountCommand::ReplaceMount(target, source, fstype, opts, old_ops) => {
-            let mount = Mount::new(target, source, fstype, old_ops);
-
-            local_mounts.remove(&mount);
+        MountCommand::MoveMount(target, source, fstype, opts, old_target, info) => {
+            let mount = Mount {
+                info,
+                ..Mount::new(old_target, source, fstype, opts)
+            };
 
-            local_mounts.update(Mount { opts, ..mount })
+            without(local_mounts, &mount).update(Mount { target, ..mount })
         }
-        MountCommand::MoveMount(target, source, fstype, opts, old_target) => {
-            let mount = Mount::new(old_target, source, fstype, opts);
-
-            local_mounts.remove(&mount);
+        MountCommand::SetMounts(xs) => xs.into_iter().collect(),
+    }
+}
 
-            local_mounts.update(Mount { target, ..mount })
-        }
+/// Produces the configured mounts in response to `FstabCommand`s.
+pub fn update_fstab<S: BuildHasher + Default>(cmd: FstabCommand) -> HashSet<Mount, S> {
+    match cmd {
+        FstabCommand::SetEntries(xs) => xs.into_iter().collect(),
     }
 }
 
@@ -38,7 +67,9 @@ pub fn update_mount<S: ::std::hash::BuildHasher>(
 mod tests {
     use super::update_mount;
     use device_types::{
-        mount::{FsType, Mount, MountCommand, MountOpts, MountPoint},
+        mount::{
+            FsType, MajorMinor, Mount, MountCommand, MountInfo, MountOpts, MountPoint, Propagation,
+        },
         DevicePath,
     };
     use im::hashset;
@@ -53,6 +84,7 @@ mod tests {
             DevicePath("/dev/sde1".into()),
             FsType("ext4".to_string()),
             MountOpts("rw,relatime,data=ordered".to_string()),
+            None,
         );
 
         let mounts = update_mount(mounts, add_cmd);
@@ -65,6 +97,7 @@ mod tests {
             FsType("ext4".to_string()),
             MountOpts("rw,relatime,data=ordered".to_string()),
             MountPoint("/mnt/part1".into()),
+            None,
         );
 
         let mounts = update_mount(mounts, mv_cmd);
@@ -74,7 +107,8 @@ mod tests {
                 target: MountPoint("/mnt/part3".into()),
                 source: DevicePath("/dev/sde1".into()),
                 fs_type: FsType("ext4".to_string()),
-                opts: MountOpts("rw,relatime,data=ordered".to_string())
+                opts: MountOpts("rw,relatime,data=ordered".to_string()),
+                info: None,
             }),
             mounts
         );
@@ -85,6 +119,7 @@ mod tests {
             FsType("ext4".to_string()),
             MountOpts("r,relatime,data=ordered".to_string()),
             MountOpts("rw,relatime,data=ordered".to_string()),
+            None,
         );
 
         let mounts = update_mount(mounts, replace_cmd);
@@ -94,7 +129,8 @@ mod tests {
                 target: MountPoint("/mnt/part3".into()),
                 source: DevicePath("/dev/sde1".into()),
                 fs_type: FsType("ext4".to_string()),
-                opts: MountOpts("r,relatime,data=ordered".to_string())
+                opts: MountOpts("r,relatime,data=ordered".to_string()),
+                info: None,
             }),
             mounts
         );
@@ -104,9 +140,84 @@ mod tests {
             DevicePath("/dev/sde1".into()),
             FsType("ext4".to_string()),
             MountOpts("r,relatime,data=ordered".to_string()),
+            None,
         );
 
         let mounts = update_mount(mounts, rm_cmd);
         assert_eq!(hashset!(), mounts);
     }
+
+    #[test]
+    fn test_mount_update_with_info() {
+        let info = |mount_id, parent_id| {
+            Some(MountInfo {
+                mount_id,
+                parent_id,
+                major_minor: MajorMinor("8:65".into()),
+                root: "/".into(),
+                propagation: vec![Propagation::Shared(35)],
+            })
+        };
+
+        let cmd = |target: &str, info| {
+            MountCommand::AddMount(
+                MountPoint(target.into()),
+                DevicePath("/dev/sde1".into()),
+                FsType("ext4".to_string()),
+                MountOpts("rw".to_string()),
+                info,
+            )
+        };
+
+        let mounts = update_mount(hashset!(), cmd("/mnt/part1", info(75, 41)));
+        let mounts = update_mount(mounts, cmd("/srv/part1", info(76, 41)));
+
+        assert_eq!(mounts.len(), 2);
+
+        // Moved under a different parent, the mount is still found by its id.
+        let mv_cmd = MountCommand::MoveMount(
+            MountPoint("/mnt/other/part1".into()),
+            DevicePath("/dev/sde1".into()),
+            FsType("ext4".to_string()),
+            MountOpts("rw".to_string()),
+            MountPoint("/mnt/part1".into()),
+            info(75, 90),
+        );
+
+        let mounts = update_mount(mounts, mv_cmd);
+
+        let mut targets: Vec<_> = mounts.iter().map(|x| x.target.clone()).collect();
+        targets.sort();
+
+        assert_eq!(
+            targets,
+            vec![
+                MountPoint("/mnt/other/part1".into()),
+                MountPoint("/srv/part1".into())
+            ]
+        );
+
+        // A reused id replaces whatever had it before.
+        let mounts = update_mount(mounts, cmd("/mnt/part2", info(76, 41)));
+
+        assert_eq!(mounts.len(), 2);
+        assert!(mounts
+            .iter()
+            .all(|x| x.target.0 != std::path::Path::new("/srv/part1")));
+
+        // A reconnecting emitter replaces every mount, dropping any it no longer sees.
+        let part2 = Mount {
+            info: info(76, 41),
+            ..Mount::new(
+                MountPoint("/mnt/part2".into()),
+                DevicePath("/dev/sde1".into()),
+                FsType("ext4".to_string()),
+                MountOpts("rw".to_string()),
+            )
+        };
+
+        let mounts = update_mount(mounts, MountCommand::SetMounts(vec![part2.clone()]));
+
+        assert_eq!(mounts, hashset!(part2));
+    }
 }
@@ -4,23 +4,83 @@
 
 use device_types::{state, udev::UdevCommand};
 
-/// Mutably updates the Udev portion of the device map in response to `UdevCommand`s.
-pub fn update_udev(uevents: &state::UEvents, cmd: UdevCommand) -> state::UEvents {
-    match cmd {
-        UdevCommand::Add(x) | UdevCommand::Change(x) => uevents.update(x.devpath.clone(), x),
-        UdevCommand::Remove(x) => uevents.without(&x.devpath),
+/// How many uevents the `SEQNUM` of a removed devpath is kept for.
+///
+/// `SEQNUM` is global, so once this many newer events have been emitted,
+/// nothing emitted before the removal is expected to still be in flight.
+const TOMBSTONE_WINDOW: i64 = 10_000;
+
+/// Forgets the `SEQNUM`s of devpaths that were removed more than `TOMBSTONE_WINDOW` events before `seqnum`.
+fn prune(seqnums: &mut state::Seqnums, uevents: &state::UEvents, seqnum: i64) {
+    seqnums.retain(|p, x| uevents.contains_key(p) || *x > seqnum - TOMBSTONE_WINDOW);
+}
+
+/// Updates the Udev portion of the device map in response to `UdevCommand`s.
+///
+/// Each udev event is forwarded by a separate process, so events for a devpath
+/// can arrive out of order. Events with a `SEQNUM` at or below the last one
+/// applied for the same devpath are stale and yield `None`.
+///
+/// The `SEQNUM` of a removed devpath is kept for `TOMBSTONE_WINDOW` events,
+/// and pruned when a later device is removed.
+pub fn update_udev(
+    uevents: &state::UEvents,
+    seqnums: &state::Seqnums,
+    cmd: UdevCommand,
+) -> Option<(state::UEvents, state::Seqnums)> {
+    let (x, old) = match &cmd {
+        UdevCommand::Add(x) | UdevCommand::Change(x) | UdevCommand::Remove(x) => (x, None),
+        UdevCommand::Move(x, old) => (x, Some(old)),
+    };
+
+    for p in std::iter::once(&x.devpath).chain(old) {
+        if let Some(last) = seqnums.get(p) {
+            if x.seqnum <= *last {
+                tracing::warn!(
+                    "Discarding stale uevent for {:?}. seqnum {} <= {}",
+                    p,
+                    x.seqnum,
+                    last
+                );
+
+                return None;
+            }
+        }
     }
+
+    let mut seqnums = seqnums.update(x.devpath.clone(), x.seqnum);
+
+    if let Some(old) = old {
+        seqnums.insert(old.clone(), x.seqnum);
+    }
+
+    let seqnum = x.seqnum;
+
+    let uevents = match cmd {
+        UdevCommand::Add(x) | UdevCommand::Change(x) => {
+            return Some((uevents.update(x.devpath.clone(), x), seqnums))
+        }
+        UdevCommand::Remove(x) => uevents.without(&x.devpath),
+        UdevCommand::Move(x, old) => {
+            tracing::debug!("Moving {:?} to {:?}", old, x.devpath);
+
+            uevents.without(&old).update(x.devpath.clone(), x)
+        }
+    };
+
+    prune(&mut seqnums, &uevents, seqnum);
+
+    Some((uevents, seqnums))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::update_udev;
+    use super::{update_udev, TOMBSTONE_WINDOW};
     use device_types::{udev::UdevCommand, uevent::UEvent};
     use im::{hashmap, ordset, vector};
 
-    #[test]
-    fn test_udev_update() {
-        let ev = UEvent {
+    fn uevent() -> UEvent {
+        UEvent {
             major: "253".to_string(),
             minor: "20".to_string(),
             seqnum: 3547,
@@ -64,29 +124,113 @@ mod tests {
             dm_vg_name: None,
             vg_uuid: None,
             md_uuid: None,
-        };
+        }
+    }
+
+    #[test]
+    fn test_udev_update() {
+        let ev = uevent();
 
         let mut ev2 = ev.clone();
         ev2.size = Some(100_651_001);
+        ev2.seqnum = 3548;
+
+        let mut ev3 = ev2.clone();
+        ev3.seqnum = 3549;
 
         let uevents = hashmap! {ev.devpath.clone() => ev.clone()};
 
         let add_cmd = UdevCommand::Add(ev.clone());
 
-        let uevents = update_udev(&uevents, add_cmd);
+        let (uevents, seqnums) = update_udev(&uevents, &hashmap! {}, add_cmd).unwrap();
 
         assert_eq!(hashmap! {ev.devpath.clone() => ev.clone()}, uevents);
 
         let change_cmd = UdevCommand::Change(ev2.clone());
 
-        let uevents = update_udev(&uevents, change_cmd);
+        let (uevents, seqnums) = update_udev(&uevents, &seqnums, change_cmd).unwrap();
 
-        assert_eq!(hashmap! {ev.devpath => ev2.clone()}, uevents);
+        assert_eq!(hashmap! {ev.devpath.clone() => ev2.clone()}, uevents);
 
-        let remove_cmd = UdevCommand::Remove(ev2);
+        let remove_cmd = UdevCommand::Remove(ev3);
 
-        let uevents = update_udev(&uevents, remove_cmd);
+        let (uevents, seqnums) = update_udev(&uevents, &seqnums, remove_cmd).unwrap();
 
         assert_eq!(hashmap! {}, uevents);
+        assert_eq!(hashmap! {ev.devpath => 3549}, seqnums);
+    }
+
+    #[test]
+    fn test_udev_update_discards_stale() {
+        let ev = uevent();
+
+        let mut late = ev.clone();
+        late.seqnum = 3546;
+
+        let (uevents, seqnums) =
+            update_udev(&hashmap! {}, &hashmap! {}, UdevCommand::Remove(ev.clone())).unwrap();
+
+        // A `change` that was emitted before the `remove` must not resurrect the device.
+        assert_eq!(
+            update_udev(&uevents, &seqnums, UdevCommand::Change(late)),
+            None
+        );
+
+        // Nor may a duplicate of the `remove`.
+        assert_eq!(
+            update_udev(&uevents, &seqnums, UdevCommand::Remove(ev)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_udev_prunes_removed() {
+        let ev = uevent();
+
+        let mut other = ev.clone();
+        other.devpath = "/devices/virtual/block/dm-21".into();
+        other.seqnum = ev.seqnum + TOMBSTONE_WINDOW - 1;
+
+        let (uevents, seqnums) =
+            update_udev(&hashmap! {}, &hashmap! {}, UdevCommand::Remove(ev.clone())).unwrap();
+
+        let (uevents, seqnums) =
+            update_udev(&uevents, &seqnums, UdevCommand::Remove(other.clone())).unwrap();
+
+        assert_eq!(seqnums.len(), 2);
+
+        other.seqnum += 1;
+
+        let (_, seqnums) =
+            update_udev(&uevents, &seqnums, UdevCommand::Remove(other.clone())).unwrap();
+
+        assert_eq!(hashmap! {other.devpath => other.seqnum}, seqnums);
+    }
+
+    #[test]
+    fn test_udev_move() {
+        let ev = uevent();
+
+        let mut moved = ev.clone();
+        moved.seqnum = 3548;
+        moved.devpath = "/devices/virtual/block/dm-21".into();
+
+        let (uevents, seqnums) =
+            update_udev(&hashmap! {}, &hashmap! {}, UdevCommand::Add(ev.clone())).unwrap();
+
+        let (uevents, seqnums) = update_udev(
+            &uevents,
+            &seqnums,
+            UdevCommand::Move(moved.clone(), ev.devpath.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(hashmap! {moved.devpath.clone() => moved}, uevents);
+
+        // A late `change` for the old devpath must not bring it back.
+        assert_eq!(
+            update_udev(&uevents, &seqnums, UdevCommand::Change(ev)),
+            None
+        );
     }
 }
@@ -0,0 +1,243 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Reconciles `State` with scans of sysfs and the udev database.
+//!
+//! The daemon otherwise only learns about devices from udev events, so a missed
+//! event leaves `State` wrong until the next event for that device.
+//! A scan at startup bootstraps `State`, and periodic scans reconcile it.
+
+use crate::{daemon::StateCmd, error::Result};
+pub use device_types::udev::Scan;
+use device_types::{state::UEvents, udev::UdevCommand, uevent::UEvent};
+use futures::channel::mpsc::UnboundedSender;
+use std::{fmt, io, path::PathBuf, time::Duration};
+use tokio::timer::delay_for;
+use tokio_executor::blocking;
+pub use uevent_listener::scan::Scanner;
+
+/// How `State` differed from a `Scan`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Drift {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added {:?}, {} removed {:?}",
+            self.added.len(),
+            self.added,
+            self.removed.len(),
+            self.removed
+        )
+    }
+}
+
+/// Compares `uevents` with a `Scan`, returning the commands that bring them in line.
+///
+/// Devices udev knows about but `uevents` lacks are added. Devices in `uevents`
+/// that are no longer in sysfs at all are removed. A device that is in sysfs but
+/// not yet in the udev database is left alone, as udev will send an event for it.
+pub fn reconcile(uevents: &UEvents, scan: Scan) -> (Vec<UdevCommand>, Drift) {
+    let Scan {
+        seqnum,
+        uevents: scanned,
+        present,
+    } = scan;
+
+    let mut added: Vec<UEvent> = scanned
+        .into_iter()
+        .filter(|(k, _)| !uevents.contains_key(k))
+        .map(|(_, v)| v)
+        .collect();
+
+    added.sort_by(|a, b| a.devpath.cmp(&b.devpath));
+
+    let mut removed: Vec<UEvent> = uevents
+        .values()
+        .filter(|x| !present.contains(&x.devpath))
+        .map(|x| UEvent {
+            seqnum,
+            ..x.clone()
+        })
+        .collect();
+
+    removed.sort_by(|a, b| a.devpath.cmp(&b.devpath));
+
+    let drift = Drift {
+        added: added.iter().map(|x| x.devpath.clone()).collect(),
+        removed: removed.iter().map(|x| x.devpath.clone()).collect(),
+    };
+
+    let cmds = added
+        .into_iter()
+        .map(UdevCommand::Add)
+        .chain(removed.into_iter().map(UdevCommand::Remove))
+        .collect();
+
+    (cmds, drift)
+}
+
+/// Scans on the blocking pool, as walking sysfs and the udev database can stall.
+pub async fn scan(scanner: Scanner) -> io::Result<Scan> {
+    blocking::run(move || scanner.scan()).await
+}
+
+/// Scans every `interval`, handing each `Scan` to the `state_loop`.
+pub async fn reconcile_loop(
+    scanner: Scanner,
+    interval: Duration,
+    tx: UnboundedSender<StateCmd>,
+) -> Result<()> {
+    loop {
+        delay_for(interval).await;
+
+        match scan(scanner.clone()).await {
+            Ok(x) => tx.unbounded_send(StateCmd::Reconcile(x))?,
+            Err(e) => tracing::warn!("Could not scan sysfs: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reducers;
+    use device_types::{state::State, stats::Stats, Command};
+    use std::{fs, os::unix::fs::symlink, path::Path};
+
+    fn write(p: &Path, x: &str) {
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, x).unwrap();
+    }
+
+    fn add_disk(sys: &Path, run: &Path, name: &str, minor: u32, in_db: bool) -> PathBuf {
+        let devpath = format!(
+            "devices/platform/host{}/session1/target{}:0:0/{}:0:0:0/block/{}",
+            minor / 16,
+            minor / 16,
+            minor / 16,
+            name
+        );
+
+        let dir = sys.join(&devpath);
+
+        write(
+            &dir.join("uevent"),
+            &format!("MAJOR=8\nMINOR={}\nDEVNAME={}\nDEVTYPE=disk\n", minor, name),
+        );
+        write(&dir.join("ro"), "0\n");
+
+        fs::create_dir_all(sys.join("class/block")).unwrap();
+        symlink(&dir, sys.join("class/block").join(name)).unwrap();
+
+        if in_db {
+            write(
+                &run.join(format!("udev/data/b8:{}", minor)),
+                &format!(
+                    "S:disk/by-id/scsi-{}\nE:IML_SIZE=41943040\nE:IML_IS_MPATH=0\n",
+                    name
+                ),
+            );
+        }
+
+        Path::new("/").join(devpath)
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        let sys = root.join("sys");
+        let run = root.join("run");
+
+        write(&sys.join("kernel/uevent_seqnum"), "4210\n");
+
+        let sda = add_disk(&sys, &run, "sda", 0, true);
+        let sdb = add_disk(&sys, &run, "sdb", 16, true);
+        // Not processed by udev yet.
+        let sdc = add_disk(&sys, &run, "sdc", 32, false);
+
+        let scanner = Scanner::new(&sys, &run);
+
+        let scan = scanner.scan().unwrap();
+
+        assert_eq!(scan.seqnum, 4210);
+        assert_eq!(scan.uevents.len(), 2);
+        assert_eq!(scan.present.len(), 3);
+        assert_eq!(scan.uevents[&sda].size, Some(21_474_836_480));
+        assert_eq!(scan.uevents[&sda].read_only, Some(false));
+
+        let (cmds, drift) = reconcile(&State::new().uevents, scan);
+
+        assert_eq!(
+            drift,
+            Drift {
+                added: vec![sda.clone(), sdb.clone()],
+                removed: vec![],
+            }
+        );
+
+        let mut stats = Stats::default();
+
+        let state = cmds.into_iter().fold(State::new(), |state, x| {
+            reducers::update(&state, Command::UdevCommand(x), &mut stats).unwrap()
+        });
+
+        // sdb vanishes and udev catches up with sdc.
+        fs::remove_file(sys.join("class/block/sdb")).unwrap();
+        write(&run.join("udev/data/b8:32"), "E:IML_SIZE=41943040\n");
+        write(&sys.join("kernel/uevent_seqnum"), "4302\n");
+
+        let (cmds, drift) = reconcile(&state.uevents, scanner.scan().unwrap());
+
+        assert_eq!(
+            drift,
+            Drift {
+                added: vec![sdc.clone()],
+                removed: vec![sdb.clone()],
+            }
+        );
+
+        let state = cmds.into_iter().fold(state, |state, x| {
+            reducers::update(&state, Command::UdevCommand(x), &mut stats).unwrap()
+        });
+
+        let mut xs: Vec<_> = state.uevents.keys().cloned().collect();
+        xs.sort();
+
+        assert_eq!(xs, vec![sda, sdc.clone()]);
+        assert_eq!(stats.stale_uevents, 0);
+
+        // A second pass finds nothing to do.
+        let (cmds, drift) = reconcile(&state.uevents, scanner.scan().unwrap());
+
+        assert!(cmds.is_empty());
+        assert!(drift.is_empty());
+
+        // A scan sent by `uevent-listener` removes devices that went away while it was down.
+        fs::remove_file(sys.join("class/block/sda")).unwrap();
+        write(&sys.join("kernel/uevent_seqnum"), "4350\n");
+
+        let state = reducers::update(
+            &state,
+            Command::Reconcile(scanner.scan().unwrap()),
+            &mut stats,
+        )
+        .unwrap();
+
+        assert_eq!(state.uevents.keys().collect::<Vec<_>>(), vec![&sdc]);
+        assert_eq!(stats.stale_uevents, 0);
+    }
+}
@@ -9,17 +9,19 @@
 
 use crate::error::{self, Result};
 use device_types::{
+    client::ClientMount,
     devices::{
-        Dataset, Device, LogicalVolume, MdRaid, Mpath, Partition, Root, ScsiDevice, VolumeGroup,
-        Zpool,
+        mpath_id, partition_id, scsi_id, Dataset, Device, Diagnostic, LogicalVolume, MdRaid,
+        Mounts, Mpath, Partition, Paths, Root, ScsiDevice, VolumeGroup, Zpool,
     },
     get_vdev_paths,
-    mount::Mount,
+    mount::{FsUsage, MajorMinor, Mount, MountInfo},
     state,
     uevent::UEvent,
     DevicePath,
 };
-use im::{ordset, vector, HashSet, OrdSet, Vector};
+use im::{ordset, HashSet, OrdSet, Vector};
+use std::{collections::HashMap, hash::Hash, path::Path};
 
 /// Filter out any devices that are not suitable for mounting a filesystem.
 fn keep_usable(x: &UEvent) -> bool {
@@ -48,21 +50,92 @@ fn format_major_minor(major: &str, minor: &str) -> String {
     format!("{}:{}", major, minor)
 }
 
-fn find_by_major_minor(xs: &Vector<String>, major: &str, minor: &str) -> bool {
-    xs.contains(&format_major_minor(major, minor))
-}
+/// Finds the mounts of a block device.
+///
+/// Mounts are matched by `major:minor`, so the path a device was mounted by does not matter.
+/// Mounts reported without one are matched by source against the device `paths`.
+fn find_mounts(x: &UEvent, ys: &HashSet<Mount>) -> Mounts {
+    let major_minor = format_major_minor(&x.major, &x.minor);
 
-fn find_mount<'a>(xs: &OrdSet<DevicePath>, ys: &'a HashSet<Mount>) -> Option<&'a Mount> {
     ys.iter()
-        .find(|Mount { source, .. }| xs.iter().any(|x| x == source))
+        .filter(|y| match &y.info {
+            Some(MountInfo {
+                major_minor: MajorMinor(mm),
+                ..
+            }) => mm == &major_minor,
+            None => x.paths.contains(&y.source),
+        })
+        .cloned()
+        .collect()
 }
 
-fn get_vgs(b: &Buckets, major: &str, minor: &str) -> Result<HashSet<Device>> {
-    b.dms
-        .iter()
-        .filter(|&x| find_by_major_minor(&x.dm_slave_mms, major, minor))
-        .map(|x| {
+/// Finds the mounts of a ZFS pool or dataset, which has no device of its own, by `name`.
+fn find_zfs_mounts(name: &str, ys: &HashSet<Mount>) -> Mounts {
+    let name = DevicePath(name.into());
+
+    ys.iter().filter(|y| y.source == name).cloned().collect()
+}
+
+/// The names of every known pool and dataset, as the source of a ZFS-backed mount reads.
+pub fn zfs_names(zed_events: &state::ZedEvents) -> HashSet<DevicePath> {
+    zed_events
+        .values()
+        .flat_map(|p| {
+            std::iter::once(p.name.clone()).chain(p.datasets.iter().map(|d| d.name.clone()))
+        })
+        .map(|x| DevicePath(x.into()))
+        .collect()
+}
+
+/// Builds a `Device` from each of `xs`.
+///
+/// Devices that cannot be built are left out of the graph,
+/// and the reason is recorded in `diagnostics` against their `id`.
+fn build_each<T>(
+    xs: impl IntoIterator<Item = T>,
+    id: impl Fn(&T) -> String,
+    f: impl Fn(T) -> Result<Device>,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    let mut ys = HashSet::new();
+
+    for x in xs {
+        let device = id(&x);
+
+        match f(x) {
+            Ok(y) => {
+                ys.insert(y);
+            }
+            Err(e) => {
+                let reason = e.to_string();
+
+                tracing::debug!("Leaving {} out of the device graph: {}", device, reason);
+
+                diagnostics.insert(Diagnostic { device, reason });
+            }
+        }
+    }
+
+    ys
+}
+
+fn get_vgs(
+    b: &Buckets,
+    major: &str,
+    minor: &str,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup(&b.dms_by_slave, &format_major_minor(major, minor)),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let uuid = x
+                .vg_uuid
+                .clone()
+                .ok_or_else(|| error::none_error("Expected vg_uuid"))?;
+
             Ok(Device::VolumeGroup(VolumeGroup {
+                id: format!("vg:{}", uuid),
                 name: x
                     .dm_vg_name
                     .clone()
@@ -71,13 +144,11 @@ fn get_vgs(b: &Buckets, major: &str, minor: &str) -> Result<HashSet<Device>> {
                 size: x
                     .dm_vg_size
                     .ok_or_else(|| error::none_error("Expected Size"))?,
-                uuid: x
-                    .vg_uuid
-                    .clone()
-                    .ok_or_else(|| error::none_error("Expected vg_uuid"))?,
+                uuid,
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
 fn get_partitions(
@@ -85,22 +156,23 @@ fn get_partitions(
     ys: &HashSet<Mount>,
     major: &str,
     minor: &str,
-) -> Result<HashSet<Device>> {
-    b.partitions
-        .iter()
-        .filter(|&x| match x.part_entry_mm {
-            Some(ref p) => p == &format_major_minor(major, minor),
-            None => false,
-        })
-        .map(|x| {
-            let mount = find_mount(&x.paths, ys);
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup(&b.partitions_by_disk, &format_major_minor(major, minor)),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let mounts = find_mounts(x, ys);
+
+            let partition_number = x
+                .part_entry_number
+                .ok_or_else(|| error::none_error("Expected part_entry_number"))?;
 
             Ok(Device::Partition(Partition {
+                id: partition_id(x, partition_number),
                 serial: x.scsi83.clone(),
                 scsi80: x.scsi80.clone(),
-                partition_number: x
-                    .part_entry_number
-                    .ok_or_else(|| error::none_error("Expected part_entry_number"))?,
+                partition_number,
                 devpath: x.devpath.clone(),
                 major: x.major.clone(),
                 minor: x.minor.clone(),
@@ -110,53 +182,70 @@ fn get_partitions(
                 fs_uuid: x.fs_uuid.clone(),
                 fs_label: x.fs_label.clone(),
                 children: ordset![],
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
-fn get_lvs(b: &Buckets, ys: &HashSet<Mount>, uuid: &str) -> Result<HashSet<Device>> {
-    b.dms
-        .iter()
-        .filter(|&x| match x.vg_uuid {
-            Some(ref p) => p == uuid,
-            None => false,
-        })
-        .map(|x| {
-            let mount = find_mount(&x.paths, ys);
+fn get_lvs(
+    b: &Buckets,
+    ys: &HashSet<Mount>,
+    uuid: &str,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup(&b.lvs_by_vg, uuid),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let mounts = find_mounts(x, ys);
+
+            let uuid = x
+                .lv_uuid
+                .clone()
+                .ok_or_else(|| error::none_error("Expected lv_uuid"))?;
 
             Ok(Device::LogicalVolume(LogicalVolume {
+                id: format!("lv:{}", uuid),
                 name: x
                     .dm_lv_name
                     .clone()
                     .ok_or_else(|| error::none_error("Expected dm_lv_name"))?,
                 devpath: x.devpath.clone(),
-                uuid: x
-                    .lv_uuid
-                    .clone()
-                    .ok_or_else(|| error::none_error("Expected lv_uuid"))?,
+                uuid,
                 size: x.size.ok_or_else(|| error::none_error("Expected size"))?,
                 major: x.major.clone(),
                 minor: x.minor.clone(),
                 paths: x.paths.clone(),
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
                 filesystem_type: x.fs_type.clone(),
                 fs_uuid: x.fs_uuid.clone(),
                 fs_label: x.fs_label.clone(),
                 children: ordset![],
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
-fn get_scsis(b: &Buckets, ys: &HashSet<Mount>) -> Result<HashSet<Device>> {
-    b.rest
-        .iter()
-        .map(|x| {
-            let mount = find_mount(&x.paths, ys);
+fn get_scsis(
+    b: &Buckets,
+    ys: &HashSet<Mount>,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        b.rest.iter(),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let mounts = find_mounts(x, ys);
 
             Ok(Device::ScsiDevice(ScsiDevice {
+                id: scsi_id(x),
                 serial: x.scsi83.clone(),
                 scsi80: x.scsi80.clone(),
                 devpath: x.devpath.clone(),
@@ -168,10 +257,13 @@ fn get_scsis(b: &Buckets, ys: &HashSet<Mount>) -> Result<HashSet<Device>> {
                 fs_label: x.fs_label.clone(),
                 paths: x.paths.clone(),
                 children: ordset![],
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
 fn get_mpaths(
@@ -179,20 +271,24 @@ fn get_mpaths(
     ys: &HashSet<Mount>,
     major: &str,
     minor: &str,
-) -> Result<HashSet<Device>> {
-    b.mpaths
-        .iter()
-        .filter(|&x| find_by_major_minor(&x.dm_slave_mms, major, minor))
-        .map(|x| {
-            let mount = find_mount(&x.paths, ys);
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup(&b.mpaths_by_slave, &format_major_minor(major, minor)),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let mounts = find_mounts(x, ys);
+
+            let dm_name = x
+                .dm_name
+                .clone()
+                .ok_or_else(|| error::none_error("Mpath device did not have a name"))?;
 
             Ok(Device::Mpath(Mpath {
+                id: mpath_id(x.scsi83.as_ref(), &dm_name),
                 serial: x.scsi83.clone(),
                 scsi80: x.scsi80.clone(),
-                dm_name: x
-                    .dm_name
-                    .clone()
-                    .ok_or_else(|| error::none_error("Mpath device did not have a name"))?,
+                dm_name,
                 size: x.size.ok_or_else(|| error::none_error("Expected size"))?,
                 major: x.major.clone(),
                 minor: x.minor.clone(),
@@ -202,112 +298,136 @@ fn get_mpaths(
                 fs_label: x.fs_label.clone(),
                 children: ordset![],
                 devpath: x.devpath.clone(),
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
 fn get_mds(
     b: &Buckets,
     ys: &HashSet<Mount>,
     paths: &OrdSet<DevicePath>,
-) -> Result<HashSet<Device>> {
-    b.mds
-        .iter()
-        .filter(|&x| paths.clone().intersection(x.md_devs.clone()).is_empty())
-        .map(|x| {
-            let mount = find_mount(&x.paths, ys);
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup_all(&b.mds_by_member, paths),
+        |x| x.devpath.display().to_string(),
+        |x| {
+            let mounts = find_mounts(x, ys);
+
+            let uuid = x
+                .md_uuid
+                .clone()
+                .ok_or_else(|| error::none_error("Expected md_uuid"))?;
 
             Ok(Device::MdRaid(MdRaid {
+                id: format!("md:{}", uuid),
                 paths: x.paths.clone(),
                 filesystem_type: x.fs_type.clone(),
                 fs_uuid: x.fs_uuid.clone(),
                 fs_label: x.fs_label.clone(),
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
                 major: x.major.clone(),
                 minor: x.minor.clone(),
                 size: x.size.ok_or_else(|| error::none_error("Expected size"))?,
                 children: ordset![],
-                uuid: x
-                    .md_uuid
-                    .clone()
-                    .ok_or_else(|| error::none_error("Expected md_uuid"))?,
+                uuid,
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
 fn get_pools(
     b: &Buckets,
     ys: &HashSet<Mount>,
     paths: &OrdSet<DevicePath>,
-) -> Result<HashSet<Device>> {
-    b.pools
-        .iter()
-        .filter(|&x| {
-            let vdev_paths = get_vdev_paths(&x.vdev.clone());
-
-            !paths.clone().intersection(vdev_paths.into()).is_empty()
-        })
-        .map(|x| {
-            let mount = find_mount(&ordset![x.name.clone().into()], ys);
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    build_each(
+        lookup_all(&b.pools_by_vdev, paths),
+        |x| x.name.clone(),
+        |x| {
+            let mounts = find_zfs_mounts(&x.name, ys);
 
             Ok(Device::Zpool(Zpool {
+                id: format!("zpool:{}", x.guid),
                 guid: x.guid,
                 health: x.health.clone(),
                 name: x.name.clone(),
-                mount: mount.map(ToOwned::to_owned),
+                mounts,
+                usage: None,
+                unmounted: ordset![],
                 props: x.props.clone(),
                 state: x.state.clone(),
                 vdev: x.vdev.clone(),
                 size: x.size.parse()?,
                 children: ordset![],
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
-fn get_datasets(b: &Buckets, ys: &HashSet<Mount>, guid: u64) -> Result<HashSet<Device>> {
+fn get_datasets(
+    b: &Buckets,
+    ys: &HashSet<Mount>,
+    guid: u64,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) -> HashSet<Device> {
+    // Zpools are only built from pools in the buckets, so the lookup cannot miss.
     let ds = b
         .pools
-        .iter()
-        .find(|p| p.guid == guid)
-        .map(|p| &p.datasets)
-        .ok_or_else(|| {
-            error::none_error(format!(
-                "Could not find pool with guid: {} in buckets",
-                guid
-            ))
-        })?;
-
-    ds.iter()
-        .map(|x| {
-            let mount = find_mount(&ordset![x.name.clone().into()], ys);
+        .get(&guid)
+        .into_iter()
+        .flat_map(|p| p.datasets.iter());
+
+    build_each(
+        ds,
+        |x| x.name.clone(),
+        |x| {
+            let mounts = find_zfs_mounts(&x.name, ys);
+
+            let guid = x.guid.parse::<u64>()?;
 
             Ok(Device::Dataset(Dataset {
+                id: format!("dataset:{}", guid),
                 name: x.name.clone(),
-                mount: mount.map(ToOwned::to_owned),
-                guid: x.guid.parse::<u64>()?,
+                mounts,
+                usage: None,
+                unmounted: ordset![],
+                guid,
                 kind: x.kind.clone(),
                 props: x.props.clone(),
             }))
-        })
-        .collect()
+        },
+        diagnostics,
+    )
 }
 
-fn build_device_graph<'a>(ptr: &mut Device, b: &Buckets<'a>, ys: &HashSet<Mount>) -> Result<()> {
+fn build_device_graph<'a>(
+    ptr: &mut Device,
+    b: &Buckets<'a>,
+    ys: &HashSet<Mount>,
+    diagnostics: &mut OrdSet<Diagnostic>,
+) {
     match ptr {
         Device::Root(r) => {
-            let ss = get_scsis(&b, &ys)?;
+            let ss = get_scsis(&b, &ys, diagnostics);
 
             for mut x in ss {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 r.children.insert(x);
             }
 
-            Ok(())
+            r.diagnostics = diagnostics.clone();
         }
         Device::Mpath(Mpath {
             children,
@@ -316,21 +436,19 @@ fn build_device_graph<'a>(ptr: &mut Device, b: &Buckets<'a>, ys: &HashSet<Mount>
             paths,
             ..
         }) => {
-            let vs = get_vgs(&b, major, minor)?;
+            let vs = get_vgs(&b, major, minor, diagnostics);
 
-            let ps = get_partitions(&b, &ys, major, minor)?;
+            let ps = get_partitions(&b, &ys, major, minor, diagnostics);
 
-            let mds = get_mds(&b, &ys, &paths)?;
+            let mds = get_mds(&b, &ys, &paths, diagnostics);
 
-            let pools = get_pools(&b, &ys, &paths)?;
+            let pools = get_pools(&b, &ys, &paths, diagnostics);
 
             for mut x in HashSet::unions(vec![vs, ps, mds, pools]) {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
         Device::ScsiDevice(ScsiDevice {
             children,
@@ -346,35 +464,31 @@ fn build_device_graph<'a>(ptr: &mut Device, b: &Buckets<'a>, ys: &HashSet<Mount>
             minor,
             ..
         }) => {
-            let xs = get_partitions(&b, &ys, &major, &minor)?;
+            let xs = get_partitions(&b, &ys, &major, &minor, diagnostics);
 
             // This should only be present for scsi devs
-            let ms = get_mpaths(&b, &ys, major, minor)?;
+            let ms = get_mpaths(&b, &ys, major, minor, diagnostics);
 
-            let vs = get_vgs(b, major, minor)?;
+            let vs = get_vgs(b, major, minor, diagnostics);
 
-            let mds = get_mds(&b, &ys, &paths)?;
+            let mds = get_mds(&b, &ys, &paths, diagnostics);
 
-            let pools = get_pools(&b, &ys, &paths)?;
+            let pools = get_pools(&b, &ys, &paths, diagnostics);
 
             for mut x in HashSet::unions(vec![xs, ms, vs, mds, pools]) {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
         Device::VolumeGroup(VolumeGroup { children, uuid, .. }) => {
-            let lvs = get_lvs(&b, &ys, &uuid)?;
+            let lvs = get_lvs(&b, &ys, &uuid, diagnostics);
 
             for mut x in lvs {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
         Device::LogicalVolume(LogicalVolume {
             major,
@@ -383,17 +497,15 @@ fn build_device_graph<'a>(ptr: &mut Device, b: &Buckets<'a>, ys: &HashSet<Mount>
             paths,
             ..
         }) => {
-            let ps = get_partitions(&b, &ys, &major, &minor)?;
+            let ps = get_partitions(&b, &ys, &major, &minor, diagnostics);
 
-            let pools = get_pools(&b, &ys, &paths)?;
+            let pools = get_pools(&b, &ys, &paths, diagnostics);
 
             for mut x in HashSet::unions(vec![ps, pools]) {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
         Device::MdRaid(MdRaid {
             major,
@@ -402,66 +514,107 @@ fn build_device_graph<'a>(ptr: &mut Device, b: &Buckets<'a>, ys: &HashSet<Mount>
             paths,
             ..
         }) => {
-            let vs = get_vgs(&b, &major, &minor)?;
+            let vs = get_vgs(&b, &major, &minor, diagnostics);
 
-            let ps = get_partitions(&b, &ys, major, minor)?;
+            let ps = get_partitions(&b, &ys, major, minor, diagnostics);
 
-            let mds = get_mds(&b, &ys, &paths)?;
+            let mds = get_mds(&b, &ys, &paths, diagnostics);
 
-            let pools = get_pools(&b, &ys, &paths)?;
+            let pools = get_pools(&b, &ys, &paths, diagnostics);
 
             for mut x in HashSet::unions(vec![vs, ps, mds, pools]) {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
         Device::Zpool(Zpool { guid, children, .. }) => {
-            let ds = get_datasets(&b, &ys, *guid)?;
+            let ds = get_datasets(&b, &ys, *guid, diagnostics);
 
             for mut x in ds {
-                build_device_graph(&mut x, b, ys)?;
+                build_device_graph(&mut x, b, ys, diagnostics);
 
                 children.insert(x);
             }
-
-            Ok(())
         }
-        Device::Dataset { .. } => Ok(()),
+        Device::Dataset { .. } => {}
     }
 }
 
-#[derive(Debug)]
+type Index<'a, K, V> = HashMap<K, Vec<&'a V>>;
+
+/// Usable devices, indexed by what their parents look them up by.
+///
+/// Building the indexes once keeps a graph rebuild linear in the number of devices.
+#[derive(Debug, Default)]
 struct Buckets<'a> {
-    dms: Vector<&'a UEvent>,
-    mds: Vector<&'a UEvent>,
-    mpaths: Vector<&'a UEvent>,
-    partitions: Vector<&'a UEvent>,
-    pools: Vector<&'a libzfs_types::Pool>,
+    /// dm devices, keyed by the major:minor of each slave.
+    dms_by_slave: Index<'a, String, UEvent>,
+    /// dm devices, keyed by vg uuid.
+    lvs_by_vg: Index<'a, String, UEvent>,
+    /// md devices, keyed by each member path.
+    mds_by_member: Index<'a, DevicePath, UEvent>,
+    /// Multipath devices, keyed by the major:minor of each path.
+    mpaths_by_slave: Index<'a, String, UEvent>,
+    /// Partitions, keyed by the major:minor of their disk.
+    partitions_by_disk: Index<'a, String, UEvent>,
+    /// Pools, keyed by each vdev path.
+    pools_by_vdev: Index<'a, DevicePath, libzfs_types::Pool>,
+    pools: HashMap<u64, &'a libzfs_types::Pool>,
     rest: Vector<&'a UEvent>,
 }
 
+fn insert<'a, K: Hash + Eq, V>(index: &mut Index<'a, K, V>, k: K, v: &'a V) {
+    index.entry(k).or_default().push(v);
+}
+
+fn lookup<'a, 'b, K, V, Q>(index: &'b Index<'a, K, V>, k: &Q) -> &'b [&'a V]
+where
+    K: Hash + Eq + std::borrow::Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    index.get(k).map(Vec::as_slice).unwrap_or(&[])
+}
+
+/// Looks up each of `ks`, returning every match once.
+fn lookup_all<'a, 'b, K, V>(
+    index: &Index<'a, K, V>,
+    ks: impl IntoIterator<Item = &'b K>,
+) -> Vec<&'a V>
+where
+    K: Hash + Eq + 'b,
+{
+    let mut seen = std::collections::HashSet::new();
+
+    ks.into_iter()
+        .flat_map(|k| lookup(index, k))
+        .filter(|&&x| seen.insert(x as *const V))
+        .cloned()
+        .collect()
+}
+
 fn bucket_devices<'a>(xs: &Vector<&'a UEvent>, ys: &'a state::ZedEvents) -> Buckets<'a> {
-    let buckets = Buckets {
-        dms: vector![],
-        mds: vector![],
-        mpaths: vector![],
-        partitions: vector![],
-        pools: vector![],
-        rest: vector![],
-    };
+    let mut buckets = xs.iter().fold(Buckets::default(), |mut acc, &x| {
+        if is_dm(x) {
+            for mm in &x.dm_slave_mms {
+                insert(&mut acc.dms_by_slave, mm.clone(), x);
+            }
 
-    let mut buckets = xs.iter().fold(buckets, |mut acc, x| {
-        if is_dm(&x) {
-            acc.dms.push_back(x)
-        } else if is_mdraid(&x) {
-            acc.mds.push_back(x)
-        } else if is_mpath(&x) {
-            acc.mpaths.push_back(x)
-        } else if is_partition(&x) {
-            acc.partitions.push_back(x)
+            if let Some(uuid) = &x.vg_uuid {
+                insert(&mut acc.lvs_by_vg, uuid.clone(), x);
+            }
+        } else if is_mdraid(x) {
+            for p in &x.md_devs {
+                insert(&mut acc.mds_by_member, p.clone(), x);
+            }
+        } else if is_mpath(x) {
+            for mm in &x.dm_slave_mms {
+                insert(&mut acc.mpaths_by_slave, mm.clone(), x);
+            }
+        } else if is_partition(x) {
+            if let Some(mm) = &x.part_entry_mm {
+                insert(&mut acc.partitions_by_disk, mm.clone(), x);
+            }
         } else {
             acc.rest.push_back(x)
         }
@@ -469,7 +622,13 @@ fn bucket_devices<'a>(xs: &Vector<&'a UEvent>, ys: &'a state::ZedEvents) -> Buck
         acc
     });
 
-    buckets.pools = ys.values().collect();
+    for x in ys.values() {
+        for p in get_vdev_paths(&x.vdev) {
+            insert(&mut buckets.pools_by_vdev, p, x);
+        }
+
+        buckets.pools.insert(x.guid, x);
+    }
 
     buckets
 }
@@ -478,15 +637,452 @@ fn build_device_list(xs: &state::UEvents) -> Vector<&UEvent> {
     xs.values().filter(|y| keep_usable(y)).collect()
 }
 
-pub fn produce_device_graph(state: &state::State) -> Result<bytes::Bytes> {
+fn lookup_usage(mounts: &Mounts, xs: &state::FsUsages) -> Option<FsUsage> {
+    mounts.iter().find_map(|x| xs.get(&x.target)).copied()
+}
+
+/// The mounts among `ys` with a matching source whose target is not among `mounts`.
+fn find_unmounted(
+    is_source: impl Fn(&DevicePath) -> bool,
+    mounts: &Mounts,
+    ys: &HashSet<Mount>,
+) -> Mounts {
+    ys.iter()
+        .filter(|y| is_source(&y.source))
+        .filter(|y| !mounts.iter().any(|x| x.target == y.target))
+        .cloned()
+        .collect()
+}
+
+/// Fills in the `usage` and `unmounted` of every device in `x`,
+/// which depend on more than the device and its mounts.
+fn annotate(x: Device, state: &state::State) -> Device {
+    let children = |ys: OrdSet<Device>| ys.into_iter().map(|y| annotate(y, state)).collect();
+    let usage = |mounts: &Mounts| lookup_usage(mounts, &state.fs_usage);
+    let unmounted = |paths: &Paths, mounts: &Mounts| {
+        find_unmounted(|p| paths.contains(p), mounts, &state.configured_mounts)
+    };
+    let zfs_unmounted = |name: &str, mounts: &Mounts| {
+        find_unmounted(|p| p.0 == Path::new(name), mounts, &state.configured_mounts)
+    };
+
+    match x {
+        Device::Root(mut x) => {
+            x.children = children(x.children);
+            Device::Root(x)
+        }
+        Device::ScsiDevice(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = unmounted(&x.paths, &x.mounts);
+            x.children = children(x.children);
+            Device::ScsiDevice(x)
+        }
+        Device::Partition(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = unmounted(&x.paths, &x.mounts);
+            x.children = children(x.children);
+            Device::Partition(x)
+        }
+        Device::MdRaid(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = unmounted(&x.paths, &x.mounts);
+            x.children = children(x.children);
+            Device::MdRaid(x)
+        }
+        Device::Mpath(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = unmounted(&x.paths, &x.mounts);
+            x.children = children(x.children);
+            Device::Mpath(x)
+        }
+        Device::VolumeGroup(mut x) => {
+            x.children = children(x.children);
+            Device::VolumeGroup(x)
+        }
+        Device::LogicalVolume(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = unmounted(&x.paths, &x.mounts);
+            x.children = children(x.children);
+            Device::LogicalVolume(x)
+        }
+        Device::Zpool(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = zfs_unmounted(&x.name, &x.mounts);
+            x.children = children(x.children);
+            Device::Zpool(x)
+        }
+        Device::Dataset(mut x) => {
+            x.usage = usage(&x.mounts);
+            x.unmounted = zfs_unmounted(&x.name, &x.mounts);
+            Device::Dataset(x)
+        }
+    }
+}
+
+/// Builds the device tree, rooted at a `Device::Root`.
+pub fn device_tree(state: &state::State) -> Device {
     let dev_list = build_device_list(&state.uevents);
     let dev_list = bucket_devices(&dev_list, &state.zed_events);
 
-    let mut root = Device::Root(Root::default());
+    // A Lustre target on ZFS names its dataset as the source, and is attached to it.
+    let zfs = zfs_names(&state.zed_events);
+
+    let mut root = Device::Root(Root {
+        client_mounts: state
+            .local_mounts
+            .iter()
+            .filter(|x| !zfs.contains(&x.source))
+            .filter_map(ClientMount::from_mount)
+            .collect(),
+        ..Root::default()
+    });
 
-    build_device_graph(&mut root, &dev_list, &state.local_mounts)?;
+    build_device_graph(&mut root, &dev_list, &state.local_mounts, &mut ordset![]);
 
-    let v = serde_json::to_string(&root)?;
+    if state.fs_usage.is_empty() && state.configured_mounts.is_empty() {
+        root
+    } else {
+        annotate(root, state)
+    }
+}
+
+/// Serializes `x` as a single line, as sent to stream clients.
+pub fn encode(x: &impl serde::Serialize) -> Result<bytes::Bytes> {
+    let v = serde_json::to_string(x)?;
     let b = bytes::BytesMut::from(v + "\n");
     Ok(b.freeze())
 }
+
+pub fn produce_device_graph(state: &state::State) -> Result<bytes::Bytes> {
+    encode(&device_tree(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::{
+        mount::{FsType, MountOpts, MountPoint},
+        state::State,
+    };
+    use im::{hashset, vector};
+    use test_support::uevent;
+
+    fn children(x: &Device) -> Vec<&Device> {
+        match x {
+            Device::Root(Root { children, .. })
+            | Device::ScsiDevice(ScsiDevice { children, .. })
+            | Device::Partition(Partition { children, .. })
+            | Device::MdRaid(MdRaid { children, .. })
+            | Device::Mpath(Mpath { children, .. })
+            | Device::VolumeGroup(VolumeGroup { children, .. })
+            | Device::LogicalVolume(LogicalVolume { children, .. })
+            | Device::Zpool(Zpool { children, .. }) => children.iter().collect(),
+            Device::Dataset(_) => vec![],
+        }
+    }
+
+    fn kinds(x: &Device) -> Vec<String> {
+        let mut xs: Vec<String> = children(x)
+            .into_iter()
+            .map(|x| match x {
+                Device::ScsiDevice(x) => format!("scsi {}:{}", x.major, x.minor),
+                Device::Partition(x) => format!("partition {}:{}", x.major, x.minor),
+                Device::MdRaid(x) => format!("md {}:{}", x.major, x.minor),
+                Device::Mpath(x) => format!("mpath {}:{}", x.major, x.minor),
+                x => format!("{:?}", x),
+            })
+            .collect();
+
+        xs.sort();
+
+        xs
+    }
+
+    /// A mount, with `(mount_id, major:minor, root)` when it came from mountinfo.
+    fn mount(target: &str, source: &str, info: Option<(u32, &str, &str)>) -> Mount {
+        Mount {
+            info: info.map(|(mount_id, major_minor, root)| MountInfo {
+                mount_id,
+                parent_id: 1,
+                major_minor: MajorMinor(major_minor.into()),
+                root: root.into(),
+                propagation: vec![],
+            }),
+            ..Mount::new(
+                MountPoint(target.into()),
+                source.into(),
+                FsType("ext4".into()),
+                MountOpts("rw".into()),
+            )
+        }
+    }
+
+    #[test]
+    fn test_find_mounts() {
+        let by_label = mount("/mnt/a", "/dev/disk/by-label/a", Some((70, "8:16", "/")));
+        let bind = mount("/srv/a", "/dev/sdb", Some((71, "8:16", "/export")));
+        let legacy = mount("/mnt/b", "/dev/sda", None);
+        let dataset = mount("/pool/home", "pool/home", Some((72, "0:45", "/")));
+
+        let ys = hashset![
+            by_label.clone(),
+            bind.clone(),
+            legacy.clone(),
+            dataset.clone()
+        ];
+
+        assert_eq!(
+            find_mounts(&uevent("sdb", "8", "16"), &ys),
+            ordset![by_label, bind]
+        );
+        assert_eq!(find_mounts(&uevent("sda", "8", "0"), &ys), ordset![legacy]);
+
+        // A mount with a major:minor is not matched by source.
+        let sdc = UEvent {
+            paths: ordset!["/dev/disk/by-label/a".into()],
+            ..uevent("sdc", "8", "32")
+        };
+
+        assert_eq!(find_mounts(&sdc, &ys), ordset![]);
+
+        assert_eq!(find_zfs_mounts("pool/home", &ys), ordset![dataset]);
+    }
+
+    #[test]
+    fn test_device_tree_usage() {
+        let sdb = mount("/mnt/b", "/dev/sdb", Some((70, "8:16", "/")));
+
+        let usage = FsUsage {
+            bytes_total: 100,
+            bytes_used: 40,
+            bytes_available: 55,
+            ..FsUsage::default()
+        };
+
+        let state = State {
+            uevents: vec![uevent("sda", "8", "0"), uevent("sdb", "8", "16")]
+                .into_iter()
+                .map(|x| (x.devpath.clone(), x))
+                .collect(),
+            local_mounts: hashset![sdb.clone()],
+            fs_usage: vec![(sdb.target, usage)].into_iter().collect(),
+            ..State::new()
+        };
+
+        let root = device_tree(&state);
+
+        let xs: Vec<_> = children(&root)
+            .into_iter()
+            .map(|x| match x {
+                Device::ScsiDevice(x) => (x.minor.as_str(), x.usage),
+                x => panic!("Expected ScsiDevice, got {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(xs, vec![("0", None), ("16", Some(usage))]);
+    }
+
+    #[test]
+    fn test_device_tree_client_mounts() {
+        let local = mount("/mnt/b", "/dev/sdb", Some((70, "8:16", "/")));
+        let lustre = Mount {
+            fs_type: FsType("lustre".into()),
+            ..mount("/mnt/fs1", "10.0.0.1@tcp:/fs1", Some((71, "0:52", "/")))
+        };
+
+        let ost0 = Mount {
+            fs_type: FsType("lustre".into()),
+            ..mount("/mnt/ost0", "pool/ost0", Some((72, "0:53", "/")))
+        };
+
+        let sdc = UEvent {
+            paths: ordset!["/dev/sdc".into(), "/dev/disk/by-id/wwn-sdc".into()],
+            ..uevent("sdc", "8", "32")
+        };
+
+        let pool = libzfs_types::Pool {
+            datasets: vec![libzfs_types::Dataset {
+                name: "pool/ost0".into(),
+                guid: "1712542342".into(),
+                kind: "filesystem".into(),
+                props: vec![],
+            }],
+            ..test_support::pool("pool", 1, &["wwn-sdc"])
+        };
+
+        let state = State {
+            uevents: vec![uevent("sdb", "8", "16"), sdc]
+                .into_iter()
+                .map(|x| (x.devpath.clone(), x))
+                .collect(),
+            zed_events: vec![(pool.guid, pool)].into_iter().collect(),
+            local_mounts: hashset![local, lustre.clone(), ost0.clone()],
+            ..State::new()
+        };
+
+        match device_tree(&state) {
+            Device::Root(x) => assert_eq!(
+                x.client_mounts
+                    .into_iter()
+                    .map(|x| x.mount().clone())
+                    .collect::<Vec<_>>(),
+                vec![lustre]
+            ),
+            x => panic!("Expected Root, got {:?}", x),
+        }
+
+        // The ZFS-backed OST is only reported on its dataset.
+        let root = device_tree(&state);
+
+        let sdc = children(&root)
+            .into_iter()
+            .find(|x| match x {
+                Device::ScsiDevice(x) => x.minor == "32",
+                _ => false,
+            })
+            .unwrap();
+
+        match children(children(sdc)[0])[..] {
+            [Device::Dataset(x)] => assert_eq!(x.mounts, ordset![ost0]),
+            ref xs => panic!("Expected a single dataset, got {:?}", xs),
+        }
+    }
+
+    #[test]
+    fn test_device_tree_unmounted() {
+        let sdb = UEvent {
+            paths: ordset![
+                "/dev/sdb".into(),
+                "/dev/disk/by-uuid/b4550256-cf48-4013-8363-bfee5f52da12".into()
+            ],
+            ..uevent("sdb", "8", "16")
+        };
+
+        let ost0 = mount(
+            "/mnt/ost0",
+            "/dev/disk/by-uuid/b4550256-cf48-4013-8363-bfee5f52da12",
+            None,
+        );
+        let scratch = mount("/mnt/scratch", "/dev/sdb", None);
+        let other = mount("/mnt/other", "/dev/sdc", None);
+
+        let state = State {
+            uevents: vec![uevent("sda", "8", "0"), sdb]
+                .into_iter()
+                .map(|x| (x.devpath.clone(), x))
+                .collect(),
+            local_mounts: hashset![mount("/mnt/scratch", "/dev/sdb", Some((70, "8:16", "/")))],
+            configured_mounts: hashset![ost0.clone(), scratch, other],
+            ..State::new()
+        };
+
+        let xs: Vec<_> = children(&device_tree(&state))
+            .into_iter()
+            .map(|x| match x {
+                Device::ScsiDevice(x) => (x.minor.clone(), x.unmounted.clone()),
+                x => panic!("Expected ScsiDevice, got {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(
+            xs,
+            vec![
+                ("0".to_string(), ordset![]),
+                ("16".to_string(), ordset![ost0])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_produce_device_graph() {
+        let mpath = UEvent {
+            is_mpath: Some(true),
+            dm_name: Some("mpatha".to_string()),
+            dm_slave_mms: vector!["8:0".to_string(), "8:16".to_string()],
+            ..uevent("dm-0", "253", "0")
+        };
+
+        let part = UEvent {
+            part_entry_mm: Some("253:0".to_string()),
+            part_entry_number: Some(1),
+            ..uevent("dm-1", "253", "1")
+        };
+
+        let md = UEvent {
+            md_uuid: Some("0ed9fc9b:58e09ccd:0d2d7b3c:2b2f6e13".to_string()),
+            md_devs: ordset!["/dev/sdc".into()],
+            ..uevent("md0", "9", "0")
+        };
+
+        let xs = vec![
+            uevent("sda", "8", "0"),
+            uevent("sdb", "8", "16"),
+            uevent("sdc", "8", "32"),
+            mpath,
+            part,
+            md,
+        ];
+
+        let state = State {
+            uevents: xs.into_iter().map(|x| (x.devpath.clone(), x)).collect(),
+            ..State::new()
+        };
+
+        let root: Device = serde_json::from_slice(&produce_device_graph(&state).unwrap()).unwrap();
+
+        assert_eq!(kinds(&root), vec!["scsi 8:0", "scsi 8:16", "scsi 8:32"]);
+
+        for x in children(&root) {
+            match x {
+                Device::ScsiDevice(ScsiDevice { minor, .. }) if minor == "32" => {
+                    assert_eq!(kinds(x), vec!["md 9:0"]);
+                }
+                _ => {
+                    assert_eq!(kinds(x), vec!["mpath 253:0"]);
+                    assert_eq!(kinds(children(x)[0]), vec!["partition 253:1"]);
+                }
+            }
+        }
+
+        match root {
+            Device::Root(x) => assert_eq!(x.diagnostics, ordset![]),
+            x => panic!("Expected Root, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_produce_device_graph_diagnostics() {
+        let mpath = UEvent {
+            is_mpath: Some(true),
+            dm_name: None,
+            dm_slave_mms: vector!["8:0".to_string(), "8:16".to_string()],
+            ..uevent("dm-0", "253", "0")
+        };
+
+        let xs = vec![uevent("sda", "8", "0"), uevent("sdb", "8", "16"), mpath];
+
+        let state = State {
+            uevents: xs.into_iter().map(|x| (x.devpath.clone(), x)).collect(),
+            ..State::new()
+        };
+
+        let root: Device = serde_json::from_slice(&produce_device_graph(&state).unwrap()).unwrap();
+
+        assert_eq!(kinds(&root), vec!["scsi 8:0", "scsi 8:16"]);
+
+        for x in children(&root) {
+            assert_eq!(kinds(x), Vec::<String>::new());
+        }
+
+        match root {
+            Device::Root(x) => assert_eq!(
+                x.diagnostics,
+                ordset![Diagnostic {
+                    device: "/devices/virtual/block/dm-0".to_string(),
+                    reason: "Mpath device did not have a name".to_string(),
+                }]
+            ),
+            x => panic!("Expected Root, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Samples filesystem usage for local mounts.
+//!
+//! Only mounts of a block device or a ZFS pool or dataset are sampled, including
+//! Lustre targets on ZFS. They are what device nodes represent, and `statvfs` on
+//! them does not go over the network.
+//!
+//! Sampling runs on the blocking pool, outside the `state_loop`, so a hung
+//! filesystem delays its own sample rather than every other command. A `statvfs`
+//! that times out holds its blocking thread until it returns, so its target is
+//! skipped until then rather than tying up another thread each interval.
+
+use crate::{
+    daemon::StateCmd,
+    error::{self, Result},
+};
+use device_types::{
+    mount::{FsUsage, Mount, MountPoint},
+    state::FsUsages,
+    DevicePath,
+};
+use futures::{
+    channel::{mpsc::UnboundedSender, oneshot},
+    future::join_all,
+};
+use im::HashSet;
+use std::{
+    collections,
+    ffi::CString,
+    io, mem,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{future::FutureExt as _, timer::delay_for};
+use tokio_executor::blocking;
+
+/// How long a single `statvfs` may take before its mount is left out of a sample.
+pub const SAMPLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Calls `statvfs` on `path`.
+// The widths of `statvfs` fields vary between platforms.
+#[allow(clippy::useless_conversion)]
+pub fn statvfs(path: &Path) -> io::Result<FsUsage> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut x: libc::statvfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut x) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let frsize = u64::from(x.f_frsize);
+
+    Ok(FsUsage {
+        bytes_total: u64::from(x.f_blocks) * frsize,
+        bytes_used: u64::from(x.f_blocks - x.f_bfree) * frsize,
+        bytes_available: u64::from(x.f_bavail) * frsize,
+        inodes_total: u64::from(x.f_files),
+        inodes_used: u64::from(x.f_files - x.f_ffree),
+        inodes_available: u64::from(x.f_favail),
+    })
+}
+
+fn is_sampled(x: &Mount, zfs_names: &HashSet<DevicePath>) -> bool {
+    x.fs_type.0 != "swap"
+        && (x.source.0.starts_with("/dev") || x.fs_type.0 == "zfs" || zfs_names.contains(&x.source))
+}
+
+/// The mounts in `mounts` whose usage is sampled.
+///
+/// `zfs_names` are the known pools and datasets, so a Lustre target on ZFS is sampled.
+pub fn sampled_mounts(mounts: &HashSet<Mount>, zfs_names: &HashSet<DevicePath>) -> HashSet<Mount> {
+    mounts
+        .iter()
+        .filter(|x| is_sampled(x, zfs_names))
+        .cloned()
+        .collect()
+}
+
+/// Samples mounts on the blocking pool, with at most one `statvfs` per target at a time.
+#[derive(Clone)]
+pub struct Sampler {
+    timeout: Duration,
+    /// Targets whose `statvfs` has not returned yet, whether or not it timed out.
+    in_flight: Arc<Mutex<collections::HashSet<MountPoint>>>,
+}
+
+impl Sampler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            in_flight: Arc::new(Mutex::new(collections::HashSet::new())),
+        }
+    }
+
+    async fn sample_one(&self, x: Mount) -> Option<(MountPoint, FsUsage)> {
+        let target = x.target;
+
+        if !self.in_flight.lock().unwrap().insert(target.clone()) {
+            tracing::debug!("Skipping {:?}, its last sample has not returned", target.0);
+
+            return None;
+        }
+
+        let in_flight = Arc::clone(&self.in_flight);
+        let t = target.clone();
+
+        let r = blocking::run(move || {
+            let r = statvfs(&t.0);
+
+            in_flight.lock().unwrap().remove(&t);
+
+            r
+        })
+        .timeout(self.timeout)
+        .await;
+
+        match r {
+            Ok(Ok(u)) => Some((target, u)),
+            Ok(Err(e)) => {
+                tracing::debug!("Could not sample usage of {:?}: {}", target.0, e);
+
+                None
+            }
+            Err(_) => {
+                tracing::warn!("Timed out sampling usage of {:?}", target.0);
+
+                None
+            }
+        }
+    }
+
+    /// Samples every mount in `mounts`, each on the blocking pool.
+    ///
+    /// Mounts that cannot be sampled within the timeout, or whose last sample
+    /// has not returned, are left out.
+    pub async fn sample(&self, mounts: &HashSet<Mount>) -> FsUsages {
+        let xs = mounts.iter().cloned().map(|x| self.sample_one(x));
+
+        join_all(xs).await.into_iter().flatten().collect()
+    }
+}
+
+/// Samples usage every `interval`, handing each sample to the `state_loop`.
+///
+/// The mounts to sample are asked of the `state_loop` each time, so the sample
+/// itself never holds it up.
+pub async fn usage_loop(interval: Duration, tx: UnboundedSender<StateCmd>) -> Result<()> {
+    let sampler = Sampler::new(SAMPLE_TIMEOUT);
+
+    loop {
+        delay_for(interval).await;
+
+        let (mounts_tx, mounts_rx) = oneshot::channel();
+
+        tx.unbounded_send(StateCmd::GetSampledMounts(mounts_tx))?;
+
+        let mounts = mounts_rx
+            .await
+            .map_err(|_| error::none_error("State loop did not answer with mounts to sample"))?;
+
+        tx.unbounded_send(StateCmd::SetUsage(sampler.sample(&mounts).await))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_types::mount::{FsType, MountOpts};
+
+    fn mount(target: &Path, source: &str, fs_type: &str) -> Mount {
+        Mount::new(
+            MountPoint(target.into()),
+            source.into(),
+            FsType(fs_type.into()),
+            MountOpts("rw".into()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_sample() {
+        let tmp = std::env::temp_dir();
+
+        let local = mount(&tmp, "/dev/sda1", "ext4");
+        let missing = mount(&tmp.join("device-scanner-missing"), "/dev/sdb1", "ext4");
+        let nfs = mount(&tmp, "server:/export", "nfs4");
+        let swap = mount(Path::new("swap"), "/dev/dm-1", "swap");
+        let ost = mount(&tmp.join("device-scanner-ost0"), "pool/ost0", "lustre");
+        let client = mount(&tmp, "10.0.0.1@tcp:/fs", "lustre");
+
+        let mounts = sampled_mounts(
+            &im::hashset![
+                local.clone(),
+                missing.clone(),
+                nfs,
+                swap,
+                ost.clone(),
+                client
+            ],
+            &im::hashset!["pool".into(), "pool/ost0".into()],
+        );
+
+        assert_eq!(mounts, im::hashset![local.clone(), missing, ost]);
+
+        let sampler = Sampler::new(SAMPLE_TIMEOUT);
+
+        let xs = sampler.sample(&mounts).await;
+
+        assert_eq!(xs.keys().collect::<Vec<_>>(), vec![&local.target]);
+
+        let x = xs[&local.target];
+
+        assert!(x.bytes_total > 0);
+        assert!(x.bytes_used + x.bytes_available <= x.bytes_total);
+
+        // A target whose last `statvfs` has not returned is skipped.
+        sampler
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(local.target.clone());
+
+        assert_eq!(sampler.sample(&mounts).await, FsUsages::new());
+
+        sampler.in_flight.lock().unwrap().clear();
+
+        assert_eq!(sampler.sample(&mounts).await.len(), 1);
+    }
+}
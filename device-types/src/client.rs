@@ -0,0 +1,239 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Mounts that are not backed by a local device, such as NFS and Lustre clients.
+//!
+//! These never attach to a node of the device tree, so they are reported
+//! alongside it instead.
+
+use crate::mount::Mount;
+use std::path::PathBuf;
+
+/// Kernel filesystems that say nothing about storage, and are left out.
+const PSEUDO_FS_TYPES: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nfsd",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// A Lustre client mount, from a source of the form `mgsnode[:mgsnode]:/fsname[/subdir]`.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
+)]
+pub struct LustreClient {
+    /// Each MGS in failover order, as the NIDs it can be reached on.
+    pub mgs: Vec<Vec<String>>,
+    pub fsname: String,
+    /// Set when only a subdirectory of the filesystem is mounted.
+    pub subdir: Option<PathBuf>,
+    pub mount: Mount,
+}
+
+/// An NFS mount, from a source of the form `server:/export`.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
+)]
+pub struct NfsClient {
+    /// A hostname or address. IPv6 addresses keep their brackets.
+    pub server: String,
+    pub export: PathBuf,
+    pub mount: Mount,
+}
+
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
+)]
+pub enum ClientMount {
+    Lustre(LustreClient),
+    Nfs(NfsClient),
+    /// Any other mount not backed by a local device, i.e. `tmpfs`.
+    Other(Mount),
+}
+
+/// Splits a Lustre client source into its MGS NIDs, fsname and subdirectory.
+pub fn parse_lustre_source(s: &str) -> Option<(Vec<Vec<String>>, String, Option<PathBuf>)> {
+    let idx = s.find(":/")?;
+
+    let (mgs, path) = (&s[..idx], &s[idx + 2..]);
+
+    let mgs: Vec<Vec<String>> = mgs
+        .split(':')
+        .map(|x| x.split(',').map(String::from).collect())
+        .collect();
+
+    if mgs.iter().flatten().any(|x| !x.contains('@')) {
+        return None;
+    }
+
+    let mut path = path.splitn(2, '/');
+
+    let fsname = path.next().filter(|x| !x.is_empty())?.to_string();
+
+    let subdir = path
+        .next()
+        .filter(|x| !x.is_empty())
+        .map(|x| PathBuf::from(format!("/{}", x)));
+
+    Some((mgs, fsname, subdir))
+}
+
+/// Splits an NFS source into its server and export.
+pub fn parse_nfs_source(s: &str) -> Option<(String, PathBuf)> {
+    let idx = if s.starts_with('[') {
+        s.find("]:")? + 1
+    } else {
+        s.find(':')?
+    };
+
+    let (server, export) = (&s[..idx], &s[idx + 1..]);
+
+    if server.is_empty() || !export.starts_with('/') {
+        return None;
+    }
+
+    Some((server.to_string(), export.into()))
+}
+
+impl ClientMount {
+    /// Classifies `x`, unless it is backed by a local device or dataset,
+    /// is swap, or is a pseudo filesystem.
+    pub fn from_mount(x: &Mount) -> Option<Self> {
+        let fs_type = x.fs_type.0.as_str();
+        let source = x.source.0.to_string_lossy();
+
+        if source.starts_with("/dev/")
+            || fs_type == "zfs"
+            || fs_type == "swap"
+            || PSEUDO_FS_TYPES.contains(&fs_type)
+        {
+            return None;
+        }
+
+        let parsed = match fs_type {
+            "lustre" => parse_lustre_source(&source).map(|(mgs, fsname, subdir)| {
+                ClientMount::Lustre(LustreClient {
+                    mgs,
+                    fsname,
+                    subdir,
+                    mount: x.clone(),
+                })
+            }),
+            "nfs" | "nfs4" => parse_nfs_source(&source).map(|(server, export)| {
+                ClientMount::Nfs(NfsClient {
+                    server,
+                    export,
+                    mount: x.clone(),
+                })
+            }),
+            _ => None,
+        };
+
+        Some(parsed.unwrap_or_else(|| ClientMount::Other(x.clone())))
+    }
+
+    pub fn mount(&self) -> &Mount {
+        match self {
+            ClientMount::Lustre(LustreClient { mount, .. })
+            | ClientMount::Nfs(NfsClient { mount, .. })
+            | ClientMount::Other(mount) => mount,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::{FsType, MountOpts, MountPoint};
+
+    fn mount(source: &str, fs_type: &str) -> Mount {
+        Mount::new(
+            MountPoint("/mnt/x".into()),
+            source.into(),
+            FsType(fs_type.into()),
+            MountOpts("rw".into()),
+        )
+    }
+
+    #[test]
+    fn test_parse_lustre_source() {
+        assert_eq!(
+            parse_lustre_source("10.0.0.1@tcp,10.1.0.1@o2ib:10.0.0.2@tcp:/fs1"),
+            Some((
+                vec![
+                    vec!["10.0.0.1@tcp".to_string(), "10.1.0.1@o2ib".to_string()],
+                    vec!["10.0.0.2@tcp".to_string()]
+                ],
+                "fs1".to_string(),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_lustre_source("mgs@tcp:/fs1/home/user"),
+            Some((
+                vec![vec!["mgs@tcp".to_string()]],
+                "fs1".to_string(),
+                Some("/home/user".into())
+            ))
+        );
+        assert_eq!(parse_lustre_source("mgs@tcp:/"), None);
+        assert_eq!(parse_lustre_source("server:/export"), None);
+    }
+
+    #[test]
+    fn test_parse_nfs_source() {
+        assert_eq!(
+            parse_nfs_source("nfs.example.com:/export/home"),
+            Some(("nfs.example.com".to_string(), "/export/home".into()))
+        );
+        assert_eq!(
+            parse_nfs_source("[fd00::1]:/export"),
+            Some(("[fd00::1]".to_string(), "/export".into()))
+        );
+        assert_eq!(parse_nfs_source(":/export"), None);
+        assert_eq!(parse_nfs_source("server"), None);
+    }
+
+    #[test]
+    fn test_from_mount() {
+        assert_eq!(ClientMount::from_mount(&mount("/dev/sda1", "ext4")), None);
+        assert_eq!(ClientMount::from_mount(&mount("/dev/sdb", "lustre")), None);
+        assert_eq!(ClientMount::from_mount(&mount("pool/home", "zfs")), None);
+        assert_eq!(ClientMount::from_mount(&mount("proc", "proc")), None);
+
+        match ClientMount::from_mount(&mount("mgs@tcp:/fs1", "lustre")) {
+            Some(ClientMount::Lustre(x)) => assert_eq!(x.fsname, "fs1"),
+            x => panic!("Expected a Lustre client, got {:?}", x),
+        }
+
+        match ClientMount::from_mount(&mount("server:/export", "nfs4")) {
+            Some(ClientMount::Nfs(x)) => assert_eq!(x.server, "server"),
+            x => panic!("Expected an NFS client, got {:?}", x),
+        }
+
+        let tmpfs = mount("tmpfs", "tmpfs");
+
+        assert_eq!(
+            ClientMount::from_mount(&tmpfs),
+            Some(ClientMount::Other(tmpfs))
+        );
+    }
+}
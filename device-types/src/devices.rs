@@ -2,32 +2,219 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use crate::{mount, DevicePath};
+use crate::{client::ClientMount, mount, uevent::UEvent, DevicePath};
 use im::{ordset, OrdSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 type Children = OrdSet<Device>;
 pub type Paths = OrdSet<DevicePath>;
+/// Every place the device is mounted, bind mounts included.
+pub type Mounts = OrdSet<mount::Mount>;
 
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct Root {
     pub children: Children,
+    /// Devices that were left out of the graph.
+    #[serde(default)]
+    pub diagnostics: OrdSet<Diagnostic>,
+    /// Mounts not backed by a local device, such as NFS and Lustre clients.
+    #[serde(default)]
+    pub client_mounts: OrdSet<ClientMount>,
 }
 
 impl Default for Root {
     fn default() -> Self {
         Self {
             children: ordset![],
+            diagnostics: ordset![],
+            client_mounts: ordset![],
         }
     }
 }
 
+/// The `/dev/disk/by-path` name among `paths`, if any.
+fn by_path(paths: &Paths) -> Option<String> {
+    paths
+        .iter()
+        .filter_map(|x| x.0.strip_prefix("/dev/disk/by-path/").ok())
+        .map(|x| x.to_string_lossy().to_string())
+        .next()
+}
+
+fn is_numeric(x: &str) -> bool {
+    !x.is_empty() && x.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether a devpath segment is numbered in discovery order, so may change across boots.
+fn is_enumerated(x: &str) -> bool {
+    [
+        "host",
+        "session",
+        "target",
+        "rport-",
+        "end_device-",
+        "port-",
+        "expander-",
+    ]
+    .iter()
+    .any(|p| x.starts_with(p) && x[p.len()..].split(&[':', '-'][..]).all(is_numeric))
+}
+
+/// The devpath up to the block layer, without the segments the kernel numbers
+/// in discovery order. A SCSI address keeps its channel, target and lun.
+fn devpath_transport(devpath: &Path) -> Option<String> {
+    if devpath.starts_with("/devices/virtual") {
+        return None;
+    }
+
+    let xs: Vec<String> = devpath
+        .iter()
+        .filter_map(|x| x.to_str())
+        .skip_while(|x| *x != "devices")
+        .skip(1)
+        .take_while(|x| *x != "block")
+        .filter(|x| !is_enumerated(x))
+        .map(|x| {
+            let hctl: Vec<&str> = x.split(':').collect();
+
+            if hctl.len() == 4 && hctl.iter().all(|x| is_numeric(x)) {
+                hctl[1..].join(":")
+            } else {
+                x.to_string()
+            }
+        })
+        .collect();
+
+    Some(xs.join("-"))
+}
+
+/// Identifies the path a device is reached by, so each path to a LUN has its own id.
+///
+/// This is the `/dev/disk/by-path` name when udev made one, else the devpath
+/// without host, session, target and port numbers. Two paths to a LUN only share
+/// an id when neither has a by-path name and their devpaths differ in nothing but
+/// those numbers, such as two iSCSI sessions to one portal; the graph then has a
+/// single node for both. Virtual devices are not reached by a path, so have none.
+pub fn transport_id(paths: &Paths, devpath: &Path) -> Option<String> {
+    by_path(paths).or_else(|| devpath_transport(devpath))
+}
+
+/// Identifies a device the kernel made up rather than found on a bus.
+///
+/// Device-mapper devices go by their LV uuid, or VG uuid and LV name, else their dm
+/// name; a multipath map with a WWID already has it as its serial. MD devices go by
+/// their array uuid. All of these survive a reboot. Loop, nbd, zram and ram devices
+/// have no stable id, see `kernel_id`.
+fn virtual_id(x: &UEvent) -> Option<String> {
+    match x {
+        UEvent {
+            lv_uuid: Some(lv), ..
+        } => Some(format!("dm-lv:{}", lv)),
+        UEvent {
+            vg_uuid: Some(vg),
+            dm_lv_name: Some(lv),
+            ..
+        } => Some(format!("dm-lv:{}/{}", vg, lv)),
+        UEvent {
+            dm_name: Some(name),
+            ..
+        } => Some(format!("dm-name:{}", name)),
+        UEvent {
+            md_uuid: Some(uuid),
+            ..
+        } => Some(format!("md-dev:{}", uuid)),
+        _ => None,
+    }
+}
+
+/// `virtual:` and the kernel name, for a virtual device with nothing stable to go by.
+/// The name can change each time the device is set up.
+fn kernel_id(devpath: &Path) -> String {
+    format!(
+        "virtual:{}",
+        devpath
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default()
+    )
+}
+
+/// Builds the `id` of a `ScsiDevice`.
+pub fn scsi_id(x: &UEvent) -> String {
+    match (
+        x.scsi83.as_ref().or(x.scsi80.as_ref()),
+        transport_id(&x.paths, &x.devpath),
+    ) {
+        (Some(s), Some(t)) => format!("scsi:{}@{}", s, t),
+        (Some(s), None) => format!("scsi:{}", s),
+        (None, Some(t)) => format!("path:{}", t),
+        (None, None) => virtual_id(x).unwrap_or_else(|| kernel_id(&x.devpath)),
+    }
+}
+
+/// Builds the `id` of a `Partition`.
+pub fn partition_id(x: &UEvent, partition_number: u64) -> String {
+    let partuuid = x
+        .paths
+        .iter()
+        .filter_map(|x| x.0.strip_prefix("/dev/disk/by-partuuid/").ok())
+        .map(|x| x.to_string_lossy().to_string())
+        .next();
+
+    // A by-path name of a partition already ends in `-part<N>`.
+    let transport = by_path(&x.paths).or_else(|| {
+        devpath_transport(&x.devpath).map(|x| format!("{}-part{}", x, partition_number))
+    });
+
+    let id = match (&x.scsi83, partuuid, &transport) {
+        (Some(s), _, _) => format!("partition:{}-part{}", s, partition_number),
+        (None, Some(u), _) => format!("partuuid:{}", u),
+        (None, None, Some(t)) => return format!("path:{}", t),
+        // The kernel name of a virtual partition already has its number in it.
+        (None, None, None) => {
+            return virtual_id(x)
+                .map(|id| format!("{}-part{}", id, partition_number))
+                .unwrap_or_else(|| kernel_id(&x.devpath))
+        }
+    };
+
+    match transport {
+        Some(t) => format!("{}@{}", id, t),
+        None => id,
+    }
+}
+
+/// Builds the `id` of an `Mpath`.
+pub fn mpath_id(serial: Option<&String>, dm_name: &str) -> String {
+    match serial {
+        Some(x) => format!("mpath:{}", x),
+        None => format!("dm-name:{}", dm_name),
+    }
+}
+
+/// A device that could not be added to the graph, and why.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
+)]
+pub struct Diagnostic {
+    /// The devpath, or the name of a pool or dataset.
+    pub device: String,
+    pub reason: String,
+}
+
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct ScsiDevice {
+    /// A stable identifier for the device.
+    ///
+    /// `scsi:` followed by the WWN (scsi83), or the unit serial (scsi80) if there is none,
+    /// then `@` and the `transport_id`, so each path to a LUN has its own id.
+    /// Without a serial, `path:` and the `transport_id`; a virtual device without one
+    /// is named by its dm or MD uuid, else is `virtual:` and its kernel name.
+    pub id: String,
     pub serial: Option<String>,
     pub scsi80: Option<String>,
     pub major: String,
@@ -38,7 +225,14 @@ pub struct ScsiDevice {
     pub fs_uuid: Option<String>,
     pub fs_label: Option<String>,
     pub paths: Paths,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
     pub children: Children,
 }
 
@@ -46,6 +240,14 @@ pub struct ScsiDevice {
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct Partition {
+    /// A stable identifier for the device.
+    ///
+    /// `partition:` followed by the WWN of the disk and `-part` and the partition number,
+    /// or `partuuid:` and the GPT partition uuid without a WWN, then `@` and the
+    /// `transport_id` unless the partition is on a virtual device such as a multipath map.
+    /// Without either, `path:` and the `transport_id`, or the dm or MD uuid of a virtual
+    /// device and `-part` and the partition number, else `virtual:` and the kernel name.
+    pub id: String,
     pub serial: Option<String>,
     pub scsi80: Option<String>,
     pub partition_number: u64,
@@ -57,7 +259,14 @@ pub struct Partition {
     pub fs_uuid: Option<String>,
     pub fs_label: Option<String>,
     pub paths: Paths,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
     pub children: Children,
 }
 
@@ -65,6 +274,10 @@ pub struct Partition {
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct MdRaid {
+    /// A stable identifier for the device.
+    ///
+    /// `md:` followed by the array uuid.
+    pub id: String,
     pub size: u64,
     pub major: String,
     pub minor: String,
@@ -72,7 +285,14 @@ pub struct MdRaid {
     pub fs_uuid: Option<String>,
     pub fs_label: Option<String>,
     pub paths: Paths,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
     pub uuid: String,
     pub children: Children,
 }
@@ -81,6 +301,10 @@ pub struct MdRaid {
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct Mpath {
+    /// A stable identifier for the device.
+    ///
+    /// `mpath:` followed by the WWN of the LUN, or `dm-name:` and the map name if there is none.
+    pub id: String,
     pub devpath: PathBuf,
     pub serial: Option<String>,
     pub scsi80: Option<String>,
@@ -93,13 +317,24 @@ pub struct Mpath {
     pub fs_label: Option<String>,
     pub paths: Paths,
     pub children: Children,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
 }
 
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct VolumeGroup {
+    /// A stable identifier for the device.
+    ///
+    /// `vg:` followed by the VG uuid.
+    pub id: String,
     pub name: String,
     pub uuid: String,
     pub size: u64,
@@ -110,6 +345,10 @@ pub struct VolumeGroup {
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct LogicalVolume {
+    /// A stable identifier for the device.
+    ///
+    /// `lv:` followed by the LV uuid.
+    pub id: String,
     pub name: String,
     pub uuid: String,
     pub major: String,
@@ -121,13 +360,24 @@ pub struct LogicalVolume {
     pub filesystem_type: Option<String>,
     pub fs_uuid: Option<String>,
     pub fs_label: Option<String>,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
 }
 
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct Zpool {
+    /// A stable identifier for the device.
+    ///
+    /// `zpool:` followed by the pool guid.
+    pub id: String,
     pub guid: u64,
     pub name: String,
     pub health: String,
@@ -136,18 +386,36 @@ pub struct Zpool {
     pub vdev: libzfs_types::VDev,
     pub props: Vec<libzfs_types::ZProp>,
     pub children: Children,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
 }
 
 #[derive(
     Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
 )]
 pub struct Dataset {
+    /// A stable identifier for the device.
+    ///
+    /// `dataset:` followed by the dataset guid.
+    pub id: String,
     pub guid: u64,
     pub name: String,
     pub kind: String,
     pub props: Vec<libzfs_types::ZProp>,
-    pub mount: Option<mount::Mount>,
+    #[serde(default)]
+    pub mounts: Mounts,
+    /// Usage of the mounted filesystem, sampled periodically.
+    #[serde(default)]
+    pub usage: Option<mount::FsUsage>,
+    /// Configured mounts of the device that are not mounted.
+    #[serde(default)]
+    pub unmounted: Mounts,
 }
 
 #[derive(
@@ -164,3 +432,223 @@ pub enum Device {
     Zpool(Zpool),
     Dataset(Dataset),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WWN: &str = "3600140550e41a841db244a992c31e7df";
+
+    #[test]
+    fn test_transport_id() {
+        let sas = Path::new(
+            "/devices/pci0000:00/0000:00:03.0/0000:02:00.0/host7/port-7:0/expander-7:0/port-7:0:4/end_device-7:0:4/target7:0:4/7:0:4:0/block/sdc",
+        );
+
+        assert_eq!(
+            transport_id(&ordset![], sas),
+            Some("pci0000:00-0000:00:03.0-0000:02:00.0-0:4:0".to_string())
+        );
+        assert_eq!(
+            transport_id(
+                &ordset![
+                    "/dev/disk/by-path/pci-0000:02:00.0-sas-exp0x500304800000007f-phy4-lun-0"
+                        .into()
+                ],
+                sas
+            ),
+            Some("pci-0000:02:00.0-sas-exp0x500304800000007f-phy4-lun-0".to_string())
+        );
+        assert_eq!(
+            transport_id(&ordset![], Path::new("/devices/virtual/block/dm-4")),
+            None
+        );
+    }
+
+    fn uevent(devpath: &str, paths: Paths) -> UEvent {
+        UEvent {
+            major: "8".to_string(),
+            minor: "16".to_string(),
+            seqnum: 1,
+            paths,
+            devname: "/dev/sdb".into(),
+            devpath: devpath.into(),
+            devtype: "disk".to_string(),
+            vendor: None,
+            model: None,
+            serial: None,
+            fs_type: None,
+            fs_usage: None,
+            fs_uuid: None,
+            fs_label: None,
+            part_entry_number: None,
+            part_entry_mm: None,
+            size: Some(10_737_418_240),
+            rotational: None,
+            scsi80: None,
+            scsi83: None,
+            read_only: Some(false),
+            bios_boot: None,
+            zfs_reserved: None,
+            is_mpath: None,
+            dm_slave_mms: im::vector![],
+            dm_vg_size: None,
+            md_devs: ordset![],
+            dm_multipath_devpath: None,
+            dm_name: None,
+            dm_lv_name: None,
+            lv_uuid: None,
+            dm_vg_name: None,
+            vg_uuid: None,
+            md_uuid: None,
+        }
+    }
+
+    #[test]
+    fn test_scsi_id() {
+        let devpath = "/devices/platform/host3/session2/target3:0:0/3:0:0:1/block/sdb";
+
+        let sdb = UEvent {
+            scsi83: Some(WWN.to_string()),
+            ..uevent(
+                devpath,
+                ordset![
+                    "/dev/sdb".into(),
+                    "/dev/disk/by-id/wwn-0x600140550e41a841db244a992c31e7df".into(),
+                    "/dev/disk/by-path/ip-10.0.0.1:3260-iscsi-iqn.2019-10.lio:ost12-lun-1".into()
+                ],
+            )
+        };
+
+        assert_eq!(
+            scsi_id(&sdb),
+            format!(
+                "scsi:{}@ip-10.0.0.1:3260-iscsi-iqn.2019-10.lio:ost12-lun-1",
+                WWN
+            )
+        );
+
+        // A second path to the same LUN has its own id.
+        assert_eq!(
+            scsi_id(&UEvent {
+                scsi83: Some(WWN.to_string()),
+                ..uevent(
+                    "/devices/platform/host4/session3/target4:0:0/4:0:0:1/block/sdc",
+                    ordset![
+                        "/dev/disk/by-path/ip-10.0.0.2:3260-iscsi-iqn.2019-10.lio:ost12-lun-1"
+                            .into()
+                    ],
+                )
+            }),
+            format!(
+                "scsi:{}@ip-10.0.0.2:3260-iscsi-iqn.2019-10.lio:ost12-lun-1",
+                WWN
+            )
+        );
+
+        assert_eq!(
+            scsi_id(&UEvent {
+                scsi80: Some("SLIO-ORG ost12".to_string()),
+                ..uevent(devpath, ordset![])
+            }),
+            "scsi:SLIO-ORG ost12@platform-0:0:1"
+        );
+        assert_eq!(scsi_id(&uevent(devpath, ordset![])), "path:platform-0:0:1");
+        assert_eq!(
+            scsi_id(&UEvent {
+                dm_name: Some("crypt-ost12".to_string()),
+                ..uevent("/devices/virtual/block/dm-3", ordset![])
+            }),
+            "dm-name:crypt-ost12"
+        );
+        assert_eq!(
+            scsi_id(&uevent("/devices/virtual/block/nbd0", ordset![])),
+            "virtual:nbd0"
+        );
+    }
+
+    #[test]
+    fn test_partition_id() {
+        let dm = "/devices/virtual/block/dm-4";
+        let sdb1 = "/devices/platform/host3/session2/target3:0:0/3:0:0:1/block/sdb/sdb1";
+
+        assert_eq!(
+            partition_id(
+                &UEvent {
+                    scsi83: Some(WWN.to_string()),
+                    ..uevent(dm, ordset!["/dev/mapper/mpatha1".into()])
+                },
+                1
+            ),
+            format!("partition:{}-part1", WWN)
+        );
+        assert_eq!(
+            partition_id(
+                &UEvent {
+                    scsi83: Some(WWN.to_string()),
+                    ..uevent(
+                        sdb1,
+                        ordset![
+                            "/dev/disk/by-path/ip-10.0.0.1:3260-iscsi-iqn.2019-10.lio:ost12-lun-1-part1"
+                                .into()
+                        ],
+                    )
+                },
+                1
+            ),
+            format!(
+                "partition:{}-part1@ip-10.0.0.1:3260-iscsi-iqn.2019-10.lio:ost12-lun-1-part1",
+                WWN
+            )
+        );
+        assert_eq!(
+            partition_id(
+                &uevent(
+                    dm,
+                    ordset!["/dev/disk/by-partuuid/d643e32f-b6b9-4863-af8f-8950376e28da".into()]
+                ),
+                2
+            ),
+            "partuuid:d643e32f-b6b9-4863-af8f-8950376e28da"
+        );
+        assert_eq!(
+            partition_id(&uevent(sdb1, ordset![]), 1),
+            "path:platform-0:0:1-part1"
+        );
+        assert_eq!(
+            partition_id(
+                &UEvent {
+                    lv_uuid: Some("YHhHAC-Kl8u-Wrkc-uUne-RiGE-ENwi-2Hl3YT".to_string()),
+                    ..uevent(dm, ordset![])
+                },
+                1
+            ),
+            "dm-lv:YHhHAC-Kl8u-Wrkc-uUne-RiGE-ENwi-2Hl3YT-part1"
+        );
+        assert_eq!(
+            partition_id(
+                &UEvent {
+                    md_uuid: Some("0ed9fc9b:58e09ccd:0d2d7b3c:2b2f6e13".to_string()),
+                    ..uevent("/devices/virtual/block/md127/md127p2", ordset![])
+                },
+                2
+            ),
+            "md-dev:0ed9fc9b:58e09ccd:0d2d7b3c:2b2f6e13-part2"
+        );
+        assert_eq!(
+            partition_id(
+                &uevent("/devices/virtual/block/loop0/loop0p1", ordset![]),
+                1
+            ),
+            "virtual:loop0p1"
+        );
+    }
+
+    #[test]
+    fn test_mpath_id() {
+        let wwn = WWN.to_string();
+
+        assert_eq!(mpath_id(Some(&wwn), "mpatha"), format!("mpath:{}", WWN));
+        assert_eq!(mpath_id(None, "mpatha"), "dm-name:mpatha");
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! A normalized form of the device tree.
+//!
+//! The tree repeats a shared device under each of its parents
+//! (i.e. a VG under each PV, a zpool under each vdev disk).
+//! Here every device appears once in `nodes`, and `edges` carry the relationships.
+
+use crate::{
+    client::ClientMount,
+    devices::{
+        Dataset, Device, Diagnostic, LogicalVolume, MdRaid, Mpath, Partition, Root, ScsiDevice,
+        VolumeGroup, Zpool,
+    },
+};
+use im::{ordset, OrdMap, OrdSet};
+
+/// How the two ends of an `Edge` are related.
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone, Copy,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relation {
+    /// The child is a partition of the parent.
+    PartitionOf,
+    /// The parent is a path of the child multipath device.
+    SlaveOf,
+    /// The parent is a member of the child md array or volume group.
+    MemberOf,
+    /// The parent is a vdev of the child zpool.
+    VdevOf,
+    /// The child is a logical volume in the parent volume group.
+    VolumeOf,
+    /// The child is a dataset of the parent zpool.
+    DatasetOf,
+}
+
+#[derive(
+    Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
+)]
+pub struct Edge {
+    pub parent: String,
+    pub child: String,
+    pub relation: Relation,
+}
+
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize, Clone)]
+pub struct Graph {
+    /// Every device, keyed by `id`, with its children removed.
+    pub nodes: OrdMap<String, Device>,
+    pub edges: OrdSet<Edge>,
+    /// Devices that were left out of the graph.
+    #[serde(default)]
+    pub diagnostics: OrdSet<Diagnostic>,
+    /// Mounts not backed by a local device, such as NFS and Lustre clients.
+    #[serde(default)]
+    pub client_mounts: OrdSet<ClientMount>,
+}
+
+/// The `id` of a device. A `Root` is not a device, and is always `root`.
+pub fn id(d: &Device) -> String {
+    match d {
+        Device::Root(_) => "root".to_string(),
+        Device::ScsiDevice(ScsiDevice { id, .. })
+        | Device::Partition(Partition { id, .. })
+        | Device::MdRaid(MdRaid { id, .. })
+        | Device::Mpath(Mpath { id, .. })
+        | Device::VolumeGroup(VolumeGroup { id, .. })
+        | Device::LogicalVolume(LogicalVolume { id, .. })
+        | Device::Zpool(Zpool { id, .. })
+        | Device::Dataset(Dataset { id, .. }) => id.clone(),
+    }
+}
+
+fn relation(child: &Device) -> Option<Relation> {
+    match child {
+        Device::Root(_) | Device::ScsiDevice(_) => None,
+        Device::Partition(_) => Some(Relation::PartitionOf),
+        Device::Mpath(_) => Some(Relation::SlaveOf),
+        Device::MdRaid(_) | Device::VolumeGroup(_) => Some(Relation::MemberOf),
+        Device::Zpool(_) => Some(Relation::VdevOf),
+        Device::LogicalVolume(_) => Some(Relation::VolumeOf),
+        Device::Dataset(_) => Some(Relation::DatasetOf),
+    }
+}
+
+/// Splits a device into a childless copy of itself and its children.
+fn split(d: &Device) -> (Device, OrdSet<Device>) {
+    let mut d = d.clone();
+
+    let children = match &mut d {
+        Device::Root(Root { children, .. })
+        | Device::ScsiDevice(ScsiDevice { children, .. })
+        | Device::Partition(Partition { children, .. })
+        | Device::MdRaid(MdRaid { children, .. })
+        | Device::Mpath(Mpath { children, .. })
+        | Device::VolumeGroup(VolumeGroup { children, .. })
+        | Device::LogicalVolume(LogicalVolume { children, .. })
+        | Device::Zpool(Zpool { children, .. }) => std::mem::replace(children, ordset![]),
+        Device::Dataset(_) => ordset![],
+    };
+
+    (d, children)
+}
+
+fn insert(g: &mut Graph, d: &Device) {
+    let (node, children) = split(d);
+
+    let parent = id(&node);
+
+    for x in children.iter() {
+        if let Some(relation) = relation(x) {
+            g.edges.insert(Edge {
+                parent: parent.clone(),
+                child: id(x),
+                relation,
+            });
+        }
+
+        insert(g, x);
+    }
+
+    g.nodes.insert(parent, node);
+}
+
+impl From<&Device> for Graph {
+    /// Flattens a device tree.
+    ///
+    /// A `Root` is not a node itself; its children are the nodes without a parent.
+    fn from(d: &Device) -> Self {
+        let mut g = Graph::default();
+
+        match d {
+            Device::Root(Root {
+                children,
+                diagnostics,
+                client_mounts,
+            }) => {
+                for x in children.iter() {
+                    insert(&mut g, x);
+                }
+
+                g.diagnostics = diagnostics.clone();
+                g.client_mounts = client_mounts.clone();
+            }
+            x => insert(&mut g, x),
+        }
+
+        g
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scsi(name: &str, minor: &str, children: OrdSet<Device>) -> Device {
+        Device::ScsiDevice(ScsiDevice {
+            id: format!("scsi:{}", name),
+            serial: None,
+            scsi80: None,
+            major: "8".into(),
+            minor: minor.into(),
+            devpath: format!("/devices/virtual/block/{}", name).into(),
+            size: 21_474_836_480,
+            filesystem_type: None,
+            fs_uuid: None,
+            fs_label: None,
+            paths: ordset![format!("/dev/{}", name).into()],
+            mounts: ordset![],
+            usage: None,
+            unmounted: ordset![],
+            children,
+        })
+    }
+
+    fn vg() -> Device {
+        Device::VolumeGroup(VolumeGroup {
+            id: "vg:eQ3ekKhV1E5kvbVxsaFXJadrzLmYxU9B".into(),
+            name: "vg0".into(),
+            uuid: "eQ3ekKhV1E5kvbVxsaFXJadrzLmYxU9B".into(),
+            size: 42_949_672_960,
+            children: ordset![],
+        })
+    }
+
+    #[test]
+    fn test_shared_node_appears_once() {
+        let root = Device::Root(Root {
+            children: ordset![
+                scsi("sda", "0", ordset![vg()]),
+                scsi("sdb", "16", ordset![vg()])
+            ],
+            diagnostics: ordset![],
+            client_mounts: ordset![],
+        });
+
+        let g = Graph::from(&root);
+
+        let vg_id = "vg:eQ3ekKhV1E5kvbVxsaFXJadrzLmYxU9B".to_string();
+
+        assert_eq!(
+            g.nodes.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                "scsi:sda".to_string(),
+                "scsi:sdb".to_string(),
+                vg_id.clone(),
+            ]
+        );
+
+        assert_eq!(g.nodes[&vg_id], vg());
+        assert_eq!(g.nodes["scsi:sda"], scsi("sda", "0", ordset![]));
+
+        assert_eq!(
+            g.edges,
+            ordset![
+                Edge {
+                    parent: "scsi:sda".into(),
+                    child: vg_id.clone(),
+                    relation: Relation::MemberOf,
+                },
+                Edge {
+                    parent: "scsi:sdb".into(),
+                    child: vg_id,
+                    relation: Relation::MemberOf,
+                }
+            ]
+        );
+
+        assert_eq!(
+            serde_json::to_value(Relation::PartitionOf).unwrap(),
+            "partition-of"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_without_optional_fields() {
+        let g: Graph = serde_json::from_str(r#"{"nodes":{},"edges":[]}"#).unwrap();
+
+        assert_eq!(g, Graph::default());
+    }
+}
@@ -4,7 +4,9 @@
 
 #![allow(clippy::large_enum_variant)]
 
+pub mod client;
 pub mod devices;
+pub mod graph;
 pub mod udev;
 pub mod uevent;
 
@@ -133,6 +135,31 @@ pub mod message {
     }
 }
 
+pub mod monitor {
+    use crate::Command;
+    use std::time::SystemTime;
+
+    /// A `Command` accepted by the daemon, as seen by `Command::Monitor` subscribers.
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    pub struct MonitorEvent {
+        /// When the daemon received the command.
+        pub time: SystemTime,
+        /// Whether applying the command changed the daemon's `State`.
+        pub changed: bool,
+        pub command: Command,
+    }
+}
+
+pub mod stats {
+    /// Counters returned by `Command::GetStats`.
+    ///
+    /// These are kept apart from `State`, so counting a discarded command does not change it.
+    #[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+    pub struct Stats {
+        pub stale_uevents: u64,
+    }
+}
+
 pub mod state {
     use crate::{mount, uevent};
     use im::{HashMap, HashSet};
@@ -142,11 +169,25 @@ pub mod state {
 
     pub type ZedEvents = HashMap<u64, libzfs_types::Pool>;
 
-    #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+    pub type Seqnums = HashMap<PathBuf, i64>;
+
+    pub type FsUsages = HashMap<mount::MountPoint, mount::FsUsage>;
+
+    #[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
     pub struct State {
         pub uevents: UEvents,
         pub zed_events: ZedEvents,
         pub local_mounts: HashSet<mount::Mount>,
+        /// The last applied uevent `SEQNUM` per devpath.
+        /// Entries are kept for a while after a `remove`, so late events for the devpath can be discarded.
+        #[serde(default)]
+        pub seqnums: Seqnums,
+        /// The last `statvfs` sample of each local mount, keyed by target.
+        #[serde(default)]
+        pub fs_usage: FsUsages,
+        /// Mounts configured in `/etc/fstab` or systemd `.mount` units, mounted or not.
+        #[serde(default)]
+        pub configured_mounts: HashSet<mount::Mount>,
     }
 
     impl State {
@@ -155,6 +196,9 @@ pub mod state {
                 uevents: HashMap::new(),
                 zed_events: HashMap::new(),
                 local_mounts: HashSet::new(),
+                seqnums: HashMap::new(),
+                fs_usage: HashMap::new(),
+                configured_mounts: HashSet::new(),
             }
         }
     }
@@ -181,6 +225,129 @@ pub mod mount {
     )]
     pub struct MountOpts(pub String);
 
+    impl MountOpts {
+        /// The options in order, each split into its key and value, if it has one.
+        ///
+        /// Commas inside double quotes (i.e. in an SELinux `context="..."`) do not split options.
+        pub fn pairs(&self) -> Vec<(&str, Option<&str>)> {
+            let mut xs = vec![];
+            let mut start = 0;
+            let mut quoted = false;
+
+            for (i, c) in self.0.char_indices() {
+                match c {
+                    '"' => quoted = !quoted,
+                    ',' if !quoted => {
+                        xs.push(&self.0[start..i]);
+                        start = i + 1;
+                    }
+                    _ => {}
+                }
+            }
+
+            xs.push(&self.0[start..]);
+
+            xs.into_iter()
+                .filter(|x| !x.is_empty())
+                .map(|x| {
+                    let mut kv = x.splitn(2, '=');
+
+                    (kv.next().unwrap_or_default(), kv.next())
+                })
+                .collect()
+        }
+
+        /// Whether `key` is present, with or without a value.
+        pub fn contains(&self, key: &str) -> bool {
+            self.pairs().iter().any(|(k, _)| *k == key)
+        }
+
+        /// The value of the last `key=value` option for `key`.
+        pub fn get(&self, key: &str) -> Option<&str> {
+            self.pairs()
+                .into_iter()
+                .rev()
+                .find(|(k, _)| *k == key)
+                .and_then(|(_, v)| v)
+        }
+
+        /// Whether the mount is read-only. The last of `ro` and `rw` wins.
+        pub fn is_read_only(&self) -> bool {
+            self.pairs()
+                .into_iter()
+                .rev()
+                .find(|(k, _)| *k == "ro" || *k == "rw")
+                .map(|(k, _)| k == "ro")
+                .unwrap_or(false)
+        }
+
+        /// The Lustre target name, from `svname=`.
+        pub fn lustre_svname(&self) -> Option<&str> {
+            self.get("svname")
+        }
+
+        /// Whether extended attributes are enabled, from ZFS `xattr`, `xattr=` or `noxattr`.
+        pub fn zfs_xattr(&self) -> Option<bool> {
+            self.pairs()
+                .into_iter()
+                .rev()
+                .find_map(|(k, v)| match (k, v) {
+                    ("noxattr", None) | ("xattr", Some("off")) => Some(false),
+                    ("xattr", _) => Some(true),
+                    _ => None,
+                })
+        }
+    }
+
+    /// `major:minor` of the device a filesystem is mounted from, as in `/proc/self/mountinfo`.
+    #[derive(
+        Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+    )]
+    #[serde(transparent)]
+    pub struct MajorMinor(pub String);
+
+    /// An optional field of a `/proc/self/mountinfo` line.
+    #[derive(
+        Debug,
+        Clone,
+        Copy,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Hash,
+        serde::Serialize,
+        serde::Deserialize,
+    )]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Propagation {
+        /// Shared in the given peer group.
+        Shared(u32),
+        /// A slave of the given peer group.
+        Master(u32),
+        /// Receives propagation from the given peer group, the closest dominant one visible.
+        PropagateFrom(u32),
+        Unbindable,
+    }
+
+    /// What the kernel knows about a mount, beyond what `findmnt` reports by default.
+    #[derive(
+        Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+    )]
+    pub struct MountInfo {
+        /// Unique for as long as the mount exists, within its namespace.
+        pub mount_id: u32,
+        /// The `mount_id` of the mount this one sits on.
+        pub parent_id: u32,
+        pub major_minor: MajorMinor,
+        /// The directory within the filesystem that is mounted.
+        ///
+        /// `/` unless this is a bind mount of a subdirectory.
+        pub root: PathBuf,
+        #[serde(default)]
+        pub propagation: Vec<Propagation>,
+    }
+
     #[derive(
         Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, Clone,
     )]
@@ -189,6 +356,9 @@ pub mod mount {
         pub target: MountPoint,
         pub fs_type: FsType,
         pub opts: MountOpts,
+        /// Unknown when the mount was reported by plain `findmnt`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub info: Option<MountInfo>,
     }
 
     impl Mount {
@@ -203,22 +373,101 @@ pub mod mount {
                 source,
                 fs_type,
                 opts,
+                info: None,
+            }
+        }
+
+        /// Whether `self` and `other` are the same mount.
+        ///
+        /// When both have a `mount_id` that decides, so a mount is still
+        /// found after being moved under a different parent. Otherwise every field must match.
+        pub fn is(&self, other: &Mount) -> bool {
+            match (&self.info, &other.info) {
+                (Some(x), Some(y)) => x.mount_id == y.mount_id,
+                _ => self == other,
             }
         }
     }
 
-    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    /// Filesystem usage, as reported by `statvfs`.
+    #[derive(
+        Debug,
+        Default,
+        Clone,
+        Copy,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Hash,
+        serde::Serialize,
+        serde::Deserialize,
+    )]
+    pub struct FsUsage {
+        pub bytes_total: u64,
+        pub bytes_used: u64,
+        /// Available to unprivileged users, so less than `bytes_total - bytes_used`
+        /// when blocks are reserved for root.
+        pub bytes_available: u64,
+        pub inodes_total: u64,
+        pub inodes_used: u64,
+        pub inodes_available: u64,
+    }
+
+    /// The trailing `MountInfo` is left out when unknown,
+    /// so commands from older emitters still deserialize.
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
     pub enum MountCommand {
-        AddMount(MountPoint, DevicePath, FsType, MountOpts),
-        RemoveMount(MountPoint, DevicePath, FsType, MountOpts),
-        ReplaceMount(MountPoint, DevicePath, FsType, MountOpts, MountOpts),
-        MoveMount(MountPoint, DevicePath, FsType, MountOpts, MountPoint),
+        AddMount(
+            MountPoint,
+            DevicePath,
+            FsType,
+            MountOpts,
+            #[serde(default, skip_serializing_if = "Option::is_none")] Option<MountInfo>,
+        ),
+        RemoveMount(
+            MountPoint,
+            DevicePath,
+            FsType,
+            MountOpts,
+            #[serde(default, skip_serializing_if = "Option::is_none")] Option<MountInfo>,
+        ),
+        ReplaceMount(
+            MountPoint,
+            DevicePath,
+            FsType,
+            MountOpts,
+            MountOpts,
+            #[serde(default, skip_serializing_if = "Option::is_none")] Option<MountInfo>,
+        ),
+        MoveMount(
+            MountPoint,
+            DevicePath,
+            FsType,
+            MountOpts,
+            MountPoint,
+            #[serde(default, skip_serializing_if = "Option::is_none")] Option<MountInfo>,
+        ),
+        /// Replaces every local mount.
+        ///
+        /// Sent by `mount-emitter --watch` on each (re)connect, so mounts removed
+        /// while it was disconnected do not linger.
+        SetMounts(Vec<Mount>),
+    }
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    pub enum FstabCommand {
+        /// Replaces every configured mount.
+        ///
+        /// Sources are device paths (`UUID=` and friends are resolved to their
+        /// `/dev/disk/by-*` links), or dataset names for `zfs`.
+        SetEntries(Vec<Mount>),
     }
 }
 
 pub mod zed {
 
-    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
     pub enum PoolCommand {
         AddPools(Vec<libzfs_types::Pool>),
         AddPool(libzfs_types::Pool),
@@ -231,10 +480,10 @@ pub mod zed {
     }
 
     pub mod zpool {
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct Name(pub String);
 
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct Guid(pub String);
 
         impl From<u64> for Guid {
@@ -250,7 +499,7 @@ pub mod zed {
             }
         }
 
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct State(pub String);
 
         impl From<State> for String {
@@ -261,19 +510,19 @@ pub mod zed {
     }
 
     pub mod zfs {
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct Name(pub String);
     }
 
     pub mod prop {
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct Key(pub String);
 
-        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
         pub struct Value(pub String);
     }
 
-    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
     pub enum ZedCommand {
         Init,
         CreateZpool(zpool::Name, zpool::Guid, zpool::State),
@@ -288,13 +537,28 @@ pub mod zed {
     }
 }
 
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Command {
     Stream,
+    /// Streams the device graph as a `graph::Graph` instead of a tree.
+    StreamGraph,
     GetMounts,
+    Monitor,
+    GetStats,
     PoolCommand(zed::PoolCommand),
     UdevCommand(udev::UdevCommand),
     MountCommand(mount::MountCommand),
+    /// Mounts configured in `/etc/fstab` and systemd `.mount` units.
+    FstabCommand(mount::FstabCommand),
+    /// Brings the uevents in `State` in line with a scan.
+    ///
+    /// Devices udev knows about but `State` lacks are added, and devices no longer
+    /// in sysfs are removed. Sent by `uevent-listener --daemon` when it connects.
+    Reconcile(udev::Scan),
+    /// Applies every command in order, as one update.
+    ///
+    /// If any of them fails, none are applied. Read-only commands are ignored.
+    Batch(Vec<Command>),
 }
 
 #[cfg(test)]
@@ -331,8 +595,71 @@ mod tests {
                 mount::MountPoint("swap".into()),
                 "/dev/mapper/VolGroup00-LogVol01".into(),
                 mount::FsType("swap".to_string()),
-                mount::MountOpts("defaults".to_string())
+                mount::MountOpts("defaults".to_string()),
+                None
             ))
-        )
+        );
+
+        let s = "{\"MountCommand\":{\"AddMount\":[\"/mnt/part1\",\"/dev/dm-3\",\"ext4\",\"rw\",{\"mount_id\":75,\"parent_id\":41,\"major_minor\":\"253:3\",\"root\":\"/\",\"propagation\":[{\"shared\":35},\"unbindable\"]}]}}";
+
+        let result = serde_json::from_str::<Command>(s).unwrap();
+
+        assert_eq!(
+            result,
+            Command::MountCommand(mount::MountCommand::AddMount(
+                mount::MountPoint("/mnt/part1".into()),
+                "/dev/dm-3".into(),
+                mount::FsType("ext4".to_string()),
+                mount::MountOpts("rw".to_string()),
+                Some(mount::MountInfo {
+                    mount_id: 75,
+                    parent_id: 41,
+                    major_minor: mount::MajorMinor("253:3".to_string()),
+                    root: "/".into(),
+                    propagation: vec![
+                        mount::Propagation::Shared(35),
+                        mount::Propagation::Unbindable
+                    ],
+                })
+            ))
+        );
+
+        assert_eq!(serde_json::to_string(&result).unwrap(), s);
+    }
+
+    #[test]
+    fn test_mount_opts() {
+        let x = mount::MountOpts(
+            "rw,relatime,context=\"system_u:object_r:tmp_t:s0:c1,c2\",svname=fs-OST0001,ro,xattr=sa"
+                .to_string(),
+        );
+
+        assert_eq!(
+            x.pairs(),
+            vec![
+                ("rw", None),
+                ("relatime", None),
+                ("context", Some("\"system_u:object_r:tmp_t:s0:c1,c2\"")),
+                ("svname", Some("fs-OST0001")),
+                ("ro", None),
+                ("xattr", Some("sa")),
+            ]
+        );
+
+        assert!(x.contains("relatime"));
+        assert!(!x.contains("noatime"));
+        assert!(x.is_read_only());
+        assert_eq!(x.lustre_svname(), Some("fs-OST0001"));
+        assert_eq!(x.get("relatime"), None);
+        assert_eq!(x.zfs_xattr(), Some(true));
+
+        let x = mount::MountOpts("rw,noxattr,noacl".to_string());
+
+        assert!(!x.is_read_only());
+        assert_eq!(x.zfs_xattr(), Some(false));
+        assert_eq!(x.lustre_svname(), None);
+
+        // Serialization is unchanged.
+        assert_eq!(serde_json::to_string(&x).unwrap(), "\"rw,noxattr,noacl\"");
     }
 }
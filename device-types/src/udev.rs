@@ -2,11 +2,38 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use crate::uevent;
+use crate::{state, uevent};
+use std::{collections::HashSet, path::PathBuf};
 
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum UdevCommand {
     Add(uevent::UEvent),
     Change(uevent::UEvent),
     Remove(uevent::UEvent),
+    /// The device was renamed. Carries the event for the new devpath and the old devpath.
+    Move(uevent::UEvent, PathBuf),
+}
+
+impl UdevCommand {
+    pub fn uevent(&self) -> &uevent::UEvent {
+        match self {
+            UdevCommand::Add(x)
+            | UdevCommand::Change(x)
+            | UdevCommand::Remove(x)
+            | UdevCommand::Move(x, _) => x,
+        }
+    }
+}
+
+/// Every block device found by a scan of sysfs and the udev database.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Scan {
+    /// The kernel uevent seqnum, read before scanning.
+    ///
+    /// Commands built from the scan carry it, so any event emitted
+    /// after the scan started still supersedes them.
+    pub seqnum: i64,
+    pub uevents: state::UEvents,
+    /// Every block device in sysfs, including those udev has not processed yet.
+    pub present: HashSet<PathBuf>,
 }
@@ -33,17 +33,16 @@ mkdir -p %{buildroot}%{_presetdir}
 mkdir -p %{buildroot}%{_sysconfdir}/udev/rules.d
 
 cp device-scanner.{target,socket,service} %{buildroot}%{_unitdir}
-cp block-device-populator.service %{buildroot}%{_unitdir}
 cp 00-device-scanner.preset %{buildroot}%{_presetdir}
 cp device-scanner-daemon %{buildroot}%{_bindir}
+cp device-scanner %{buildroot}%{_bindir}
+cp device-scanner-replay %{buildroot}%{_bindir}
 
 cp 99-iml-device-scanner.rules %{buildroot}%{_sysconfdir}/udev/rules.d
 cp uevent-listener %{buildroot}%{_bindir}
+cp uevent-listener.service %{buildroot}%{_unitdir}
 
 cp mount-emitter.service %{buildroot}%{_unitdir}
-cp mount-populator.service %{buildroot}%{_unitdir}
-cp swap-emitter.service %{buildroot}%{_unitdir}
-cp swap-emitter.timer %{buildroot}%{_unitdir}
 cp mount-emitter %{buildroot}%{_bindir}
 
 mkdir -p %{buildroot}%{_libexecdir}/zfs/zed.d
@@ -69,14 +68,11 @@ cp zed-enhancer %{buildroot}%{_bindir}
 cp 99-iml-zed-enhancer.rules %{buildroot}%{_sysconfdir}/udev/rules.d
 
 %files
-%attr(0644,root,root)%{_unitdir}/block-device-populator.service
 %attr(0644,root,root)%{_unitdir}/device-scanner.target
 %attr(0644,root,root)%{_unitdir}/device-scanner.socket
 %attr(0644,root,root)%{_unitdir}/device-scanner.service
 %attr(0644,root,root)%{_unitdir}/mount-emitter.service
-%attr(0644,root,root)%{_unitdir}/mount-populator.service
-%attr(0644,root,root)%{_unitdir}/swap-emitter.timer
-%attr(0644,root,root)%{_unitdir}/swap-emitter.service
+%attr(0644,root,root)%{_unitdir}/uevent-listener.service
 %attr(0644,root,root)%{_unitdir}/zed-enhancer.service
 %attr(0644,root,root)%{_unitdir}/zed-enhancer.socket
 %attr(0644,root,root)%{_unitdir}/zed-populator.service
@@ -85,6 +81,8 @@ cp 99-iml-zed-enhancer.rules %{buildroot}%{_sysconfdir}/udev/rules.d
 %attr(0644,root,root)%{_sysconfdir}/udev/rules.d/99-iml-device-scanner.rules
 %attr(0644,root,root)%{_sysconfdir}/udev/rules.d/99-iml-zed-enhancer.rules
 %attr(0755,root,root)%{_bindir}/device-scanner-daemon
+%attr(0755,root,root)%{_bindir}/device-scanner
+%attr(0755,root,root)%{_bindir}/device-scanner-replay
 %attr(0755,root,root)%{_bindir}/uevent-listener
 %attr(0755,root,root)%{_bindir}/mount-emitter
 %attr(0755,root,root)%{_bindir}/zed-enhancer
@@ -95,7 +93,7 @@ cp 99-iml-zed-enhancer.rules %{buildroot}%{_sysconfdir}/udev/rules.d
 %post
 systemctl preset device-scanner.socket
 systemctl preset mount-emitter.service
-systemctl preset swap-emitter.timer
+systemctl preset uevent-listener.service
 systemctl preset zed-populator.service
 systemctl preset zed-enhancer.socket
 systemctl preset zed-enhancer.service
@@ -106,11 +104,8 @@ systemctl preset zed-enhancer.service
 %systemd_preun device-scanner.socket
 %systemd_preun device-scanner.service
 %systemd_preun mount-emitter.service
-%systemd_preun block-device-populator.service
+%systemd_preun uevent-listener.service
 %systemd_preun zed-populator.service
-%systemd_preun mount-populator.service
-%systemd_preun swap-emitter.timer
-%systemd_preun swap-emitter.service
 %systemd_preun zed-enhancer.socket
 %systemd_preun zed-enhancer.service
 
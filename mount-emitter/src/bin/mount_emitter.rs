@@ -2,8 +2,145 @@
 // Use of this source code is governed by a MIT-style
 // license that can be found in the LICENSE file.
 
-use mount_emitter::{get_write_stream, looper, stdin_to_file, write_all};
+//! Forwards mounts to `device-scanner-daemon`.
+//!
+//! Without arguments, `findmnt -P` output is read from stdin and sent over one connection.
+//! With `--list`, the current mounts and swaps are sent once, as one batch.
+//! With `--watch`, they are sent and then every change to them, over one persistent connection.
+//! Both replace the mounts the daemon has, so a reconnect also drops mounts removed meanwhile.
+//! Both also send the mounts configured in `/etc/fstab` and systemd `.mount` units,
+//! which `--watch` re-reads alongside `/proc/swaps`.
+
+use device_scanner_client::{connect_with, encode, Backoff, Result, SOCKET_PATH};
+use device_types::{mount::FstabCommand, Command};
+use mount_emitter::{
+    batch,
+    fstab::read_configured,
+    get_write_stream, looper, mount_commands,
+    mountinfo::{Snapshot, Watcher},
+    stdin_to_file, write_all,
+};
+use std::{env, io, path::Path, process::exit, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+use tokio_executor::blocking;
+use tracing_subscriber::{fmt::Subscriber, EnvFilter};
+
+/// How often `/proc/swaps` is re-read, as it cannot be polled.
+const SWAPS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sends `xs` as one batch.
+async fn send(conn: &mut UnixStream, xs: Vec<Command>) -> Result<()> {
+    if let Some(x) = batch(xs) {
+        tracing::debug!("Sending {:?}", x);
+
+        conn.write_all(encode(&x)?.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Waits on the blocking pool until the mount table changes or `SWAPS_INTERVAL` passes.
+async fn wait(watcher: &Arc<Watcher>) -> io::Result<bool> {
+    let watcher = Arc::clone(watcher);
+
+    blocking::run(move || watcher.wait(SWAPS_INTERVAL)).await
+}
+
+/// Replaces all mounts the daemon has, then forwards changes until an error occurs.
+///
+/// `backoff` is reset once the first batch has been sent, so a daemon that
+/// accepts and then closes is not hammered.
+async fn forward(
+    watcher: &Arc<Watcher>,
+    mut conn: UnixStream,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let mut old: Option<Snapshot> = None;
+    let mut old_configured = None;
+
+    loop {
+        let new = watcher.snapshot()?;
+
+        let mut xs = mount_commands(old.as_ref(), &new);
+
+        old = Some(new);
+
+        let configured = read_configured(Path::new("/"));
+
+        if old_configured.as_ref() != Some(&configured) {
+            xs.push(Command::FstabCommand(FstabCommand::SetEntries(
+                configured.clone(),
+            )));
+
+            old_configured = Some(configured);
+        }
+
+        send(&mut conn, xs).await?;
+
+        backoff.reset();
+
+        wait(watcher).await?;
+    }
+}
+
+async fn run_watch() -> Result<()> {
+    let watcher = Arc::new(Watcher::new("/proc")?);
+
+    let mut backoff = Backoff::default();
+
+    loop {
+        let conn = connect_with(Path::new(SOCKET_PATH), &mut backoff, || {
+            UnixStream::connect(SOCKET_PATH)
+        })
+        .await;
+
+        // Whatever went wrong, start over by replacing every mount.
+        if let Err(e) = forward(&watcher, conn, &mut backoff).await {
+            tracing::warn!("{}. Resending mounts in {:?}", e, backoff.duration());
+
+            backoff.wait().await;
+        }
+    }
+}
+
+async fn run_list() -> Result<()> {
+    let new = Watcher::new("/proc")?.snapshot()?;
+
+    let mut xs = mount_commands(None, &new);
+
+    xs.push(Command::FstabCommand(FstabCommand::SetEntries(
+        read_configured(Path::new("/")),
+    )));
+
+    let mut conn = UnixStream::connect(SOCKET_PATH).await?;
+
+    send(&mut conn, xs).await
+}
 
 fn main() {
-    tokio::run(looper(stdin_to_file, get_write_stream, write_all))
+    let subscriber = Subscriber::builder()
+        .with_env_filter(EnvFilter::from_default_env())
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let arg = env::args().nth(1).unwrap_or_default();
+
+    let result = tokio::runtime::Runtime::new()
+        .map_err(Into::into)
+        .and_then(|rt| {
+            rt.block_on(async {
+                match arg.as_str() {
+                    "--watch" => run_watch().await,
+                    "--list" => run_list().await,
+                    _ => looper(stdin_to_file, get_write_stream, write_all).await,
+                }
+            })
+        });
+
+    if let Err(e) = result {
+        tracing::error!("mount-emitter failed: {}", e);
+
+        exit(1);
+    }
 }
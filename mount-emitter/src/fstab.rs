@@ -0,0 +1,318 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Reads the mounts configured in `/etc/fstab` and systemd `.mount` units.
+//!
+//! Only mounts of a block device or a ZFS dataset, Lustre targets on ZFS
+//! included, are kept, as those are what the daemon can attach to a device.
+//! `noauto` entries are left out, since they are not expected to be mounted.
+//!
+//! Sources given as `UUID=`, `LABEL=`, `PARTUUID=` or `PARTLABEL=` are
+//! resolved to the `/dev/disk/by-*` link udev creates for them.
+
+use crate::mountinfo::unescape;
+use device_types::{
+    mount::{FsType, Mount, MountOpts, MountPoint},
+    DevicePath,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where `.mount` units are read from, in order of precedence, relative to the root.
+///
+/// `systemd-fstab-generator` writes its units to `run/systemd/generator`.
+const UNIT_DIRS: &[&str] = &["etc/systemd/system", "run/systemd/generator"];
+
+/// Encodes `x` the way udev names its `/dev/disk/by-*` links.
+fn udev_escape(x: &str) -> String {
+    x.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+                c.to_string()
+            } else {
+                format!("\\x{:02x}", c as u8)
+            }
+        })
+        .collect()
+}
+
+/// Resolves an fstab source to the device path it names.
+fn resolve_source(spec: &str, fs_type: &str) -> Option<DevicePath> {
+    let mut kv = spec.splitn(2, '=');
+
+    let path = match (kv.next(), kv.next().map(|x| x.trim_matches('"'))) {
+        (Some("UUID"), Some(x)) => format!("/dev/disk/by-uuid/{}", x),
+        (Some("LABEL"), Some(x)) => format!("/dev/disk/by-label/{}", udev_escape(x)),
+        (Some("PARTUUID"), Some(x)) => format!("/dev/disk/by-partuuid/{}", x),
+        (Some("PARTLABEL"), Some(x)) => format!("/dev/disk/by-partlabel/{}", udev_escape(x)),
+        _ if spec.starts_with("/dev/") || fs_type == "zfs" => spec.to_string(),
+        // A Lustre target on ZFS names its dataset; a client names its MGS and `:/fsname`.
+        _ if fs_type == "lustre" && !spec.starts_with('/') && !spec.contains(":/") => {
+            spec.to_string()
+        }
+        _ => return None,
+    };
+
+    Some(DevicePath(path.into()))
+}
+
+fn to_mount(source: &str, target: &str, fs_type: &str, opts: &str) -> Option<Mount> {
+    let opts = MountOpts(opts.to_string());
+
+    if fs_type == "swap" || opts.contains("noauto") {
+        return None;
+    }
+
+    Some(Mount::new(
+        MountPoint(target.into()),
+        resolve_source(source, fs_type)?,
+        FsType(fs_type.to_string()),
+        opts,
+    ))
+}
+
+/// Parses the contents of `/etc/fstab`.
+pub fn parse_fstab(x: &str) -> Vec<Mount> {
+    x.lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|x| {
+            let xs: Vec<String> = x.split_whitespace().map(unescape).collect();
+
+            match (xs.first(), xs.get(1), xs.get(2)) {
+                (Some(source), Some(target), Some(fs_type)) => {
+                    let opts = xs.get(3).map(|x| x.as_str()).unwrap_or("defaults");
+
+                    to_mount(source, target, fs_type, opts)
+                }
+                _ => {
+                    tracing::warn!("Skipping malformed fstab line: {:?}", x);
+
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parses the `[Mount]` section of a systemd `.mount` unit.
+pub fn parse_mount_unit(x: &str) -> Option<Mount> {
+    let mut section = "";
+    let mut keys = BTreeMap::new();
+
+    for line in x.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+        } else if section == "[Mount]" {
+            let mut kv = line.splitn(2, '=');
+
+            if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                keys.insert(k.trim(), v.trim());
+            }
+        }
+    }
+
+    to_mount(
+        keys.get("What")?,
+        keys.get("Where")?,
+        keys.get("Type").unwrap_or(&"auto"),
+        keys.get("Options").unwrap_or(&"defaults"),
+    )
+}
+
+/// Reads `path`, if it exists. Files that cannot be read are logged and skipped,
+/// so one bad file does not hide every other configured mount.
+fn read_optional(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(x) => Some(x),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::warn!("Skipping unreadable {:?}: {}", path, e);
+
+            None
+        }
+    }
+}
+
+fn read_units(dir: &Path) -> Vec<Mount> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(xs) => xs
+            .filter_map(|x| x.ok().map(|x| x.path()))
+            .filter(|x| x.extension().map(|x| x == "mount").unwrap_or(false))
+            .collect(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            tracing::warn!("Skipping unreadable {:?}: {}", dir, e);
+
+            return vec![];
+        }
+    };
+
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|p| read_optional(p))
+        .filter_map(|x| parse_mount_unit(&x))
+        .collect()
+}
+
+/// Reads every configured mount under `root`, which is `/` outside of tests.
+///
+/// A target configured in more than one place is taken from the first of
+/// `/etc/systemd/system`, `/etc/fstab` and the generated units.
+pub fn read_configured(root: &Path) -> Vec<Mount> {
+    let fstab = read_optional(&root.join("etc/fstab"))
+        .map(|x| parse_fstab(&x))
+        .unwrap_or_default();
+
+    let xs = read_units(&root.join(UNIT_DIRS[0]))
+        .into_iter()
+        .chain(fstab)
+        .chain(read_units(&root.join(UNIT_DIRS[1])));
+
+    let mut seen = BTreeSet::new();
+
+    xs.filter(|x| seen.insert(x.target.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FSTAB: &str = "\
+#
+# /etc/fstab
+#
+/dev/mapper/centos-root /                       xfs     defaults        0 0
+UUID=b4550256-cf48-4013-8363-bfee5f52da12 /boot xfs     defaults        0 0
+LABEL=fs-OST0000 /mnt/ost0 lustre defaults,_netdev 0 0
+PARTLABEL=\"my\\040data\" /mnt/with\\040space ext4 rw
+/dev/mapper/centos-swap swap                    swap    defaults        0 0
+/dev/sdc /mnt/manual ext4 noauto 0 0
+pool/home /home zfs defaults 0 0
+pool/ost1 /mnt/ost1 lustre defaults,_netdev 0 0
+10.0.0.1@tcp:/fs /mnt/fs lustre defaults,_netdev 0 0
+proc /proc proc defaults 0 0
+/dev/sdd
+";
+
+    fn mount(target: &str, source: &str, fs_type: &str, opts: &str) -> Mount {
+        Mount::new(
+            MountPoint(target.into()),
+            source.into(),
+            FsType(fs_type.into()),
+            MountOpts(opts.into()),
+        )
+    }
+
+    #[test]
+    fn test_parse_fstab() {
+        assert_eq!(
+            parse_fstab(FSTAB),
+            vec![
+                mount("/", "/dev/mapper/centos-root", "xfs", "defaults"),
+                mount(
+                    "/boot",
+                    "/dev/disk/by-uuid/b4550256-cf48-4013-8363-bfee5f52da12",
+                    "xfs",
+                    "defaults"
+                ),
+                mount(
+                    "/mnt/ost0",
+                    "/dev/disk/by-label/fs-OST0000",
+                    "lustre",
+                    "defaults,_netdev"
+                ),
+                mount(
+                    "/mnt/with space",
+                    "/dev/disk/by-partlabel/my\\x20data",
+                    "ext4",
+                    "rw"
+                ),
+                mount("/home", "pool/home", "zfs", "defaults"),
+                mount("/mnt/ost1", "pool/ost1", "lustre", "defaults,_netdev"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mount_unit() {
+        let unit = "\
+# Automatically generated by systemd-fstab-generator
+
+[Unit]
+SourcePath=/etc/fstab
+Documentation=man:fstab(5) man:systemd-fstab-generator(8)
+Before=local-fs.target
+
+[Mount]
+What=/dev/disk/by-label/fs-OST0001
+Where=/mnt/ost1
+Type=lustre
+Options=_netdev
+";
+
+        assert_eq!(
+            parse_mount_unit(unit),
+            Some(mount(
+                "/mnt/ost1",
+                "/dev/disk/by-label/fs-OST0001",
+                "lustre",
+                "_netdev"
+            ))
+        );
+        assert_eq!(parse_mount_unit("[Mount]\nWhere=/mnt/x\n"), None);
+    }
+
+    #[test]
+    fn test_read_configured() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        fs::create_dir_all(root.join(UNIT_DIRS[0])).unwrap();
+        fs::create_dir_all(root.join(UNIT_DIRS[1])).unwrap();
+
+        assert_eq!(read_configured(root), vec![]);
+
+        fs::write(
+            root.join("etc/fstab"),
+            "/dev/sdb /mnt/b ext4 defaults 0 0\n/dev/sdc /mnt/c ext4 defaults 0 0\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join(UNIT_DIRS[0]).join("mnt-b.mount"),
+            "[Mount]\nWhat=/dev/sdb\nWhere=/mnt/b\nType=xfs\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join(UNIT_DIRS[1]).join("mnt-c.mount"),
+            "[Mount]\nWhat=/dev/sdc\nWhere=/mnt/c\nType=ext4\nOptions=ro\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join(UNIT_DIRS[1]).join("mnt-d.mount"),
+            "[Mount]\nWhat=/dev/sdd\nWhere=/mnt/d\nType=ext4\n",
+        )
+        .unwrap();
+        // Not UTF-8, so cannot be read; the other units are still used.
+        fs::write(
+            root.join(UNIT_DIRS[1]).join("mnt-e.mount"),
+            b"[Mount]\nWhat=/dev/sde\nWhere=/mnt/\xff\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_configured(root),
+            vec![
+                mount("/mnt/b", "/dev/sdb", "xfs", "defaults"),
+                mount("/mnt/c", "/dev/sdc", "ext4", "defaults"),
+                mount("/mnt/d", "/dev/sdd", "ext4", "defaults"),
+            ]
+        );
+    }
+}
@@ -5,160 +5,389 @@
 //! Reads streaming mount information from stdin and forwards to `device-scanner` daemon.
 //!
 //! The `mount-emitter` crate uses `tokio` to stream stdin line by line, parse it
-//! into a `MountCommand` variant and send the serialized result to `device-scanner`.
+//! into a `MountCommand` variant and send the serialized result to `device-scanner`
+//! over one connection, which is re-established if it drops.
 //!
+//! The `mountinfo` module instead watches the kernel mount table directly,
+//! and the `fstab` module reads the mounts that are configured.
+//!
+
+pub mod fstab;
+pub mod mountinfo;
 
 use std::convert::AsRef;
 
+use device_scanner_client::{connect_with, encode, Backoff, Result as ClientResult, SOCKET_PATH};
 use device_types::{
-    mount::{FsType, MountCommand, MountOpts, MountPoint},
+    mount::{FsType, MajorMinor, MountCommand, MountInfo, MountOpts, MountPoint},
     Command, DevicePath,
 };
-use futures::{future, Future};
-use std::{
-    collections::HashMap, io::BufRead, os::unix::net::UnixStream as NetUnixStream, process::exit,
-    str,
-};
+use futures::{Future, FutureExt};
+use std::{collections::HashMap, error, fmt, io, iter::Peekable, path::Path, result, str};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::UnixStream,
-    reactor::Handle,
 };
 
-fn line_to_command(x: &[u8]) -> MountCommand {
-    let mut x: IntermediateMap = line_to_hashmap(&x);
+pub type Result<T> = result::Result<T, Error>;
+
+/// Why a line of `findmnt -P` output could not be turned into a `MountCommand`.
+#[derive(Debug)]
+pub enum Error {
+    Utf8(str::Utf8Error),
+    /// A pair without `=`.
+    MissingEquals(String),
+    UnterminatedValue(String),
+    InvalidEscape(String),
+    MissingField(String),
+    InvalidField(String, String),
+    UnknownAction(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Utf8(ref err) => write!(f, "{}", err),
+            Error::MissingEquals(ref x) => write!(f, "Expected KEY=\"VALUE\", found {}", x),
+            Error::UnterminatedValue(ref x) => write!(f, "Unterminated value for {}", x),
+            Error::InvalidEscape(ref x) => write!(f, "Invalid escape in value for {}", x),
+            Error::MissingField(ref x) => write!(f, "Missing required field {}", x),
+            Error::InvalidField(ref x, ref v) => write!(f, "Could not parse {}={}", x, v),
+            Error::UnknownAction(ref x) => write!(f, "Unknown ACTION {}", x),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            Error::Utf8(ref err) => Some(err),
+            Error::MissingEquals(_)
+            | Error::UnterminatedValue(_)
+            | Error::InvalidEscape(_)
+            | Error::MissingField(_)
+            | Error::InvalidField(_, _)
+            | Error::UnknownAction(_) => None,
+        }
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(err: str::Utf8Error) -> Self {
+        Error::Utf8(err)
+    }
+}
+
+fn required(x: &mut IntermediateMap, k: &str) -> Result<String> {
+    x.remove(k)
+        .ok_or_else(|| Error::MissingField(k.to_string()))
+}
+
+fn parse_field<T: str::FromStr>(k: &str, v: String) -> Result<T> {
+    v.parse().map_err(|_| Error::InvalidField(k.to_string(), v))
+}
+
+/// What `findmnt -o ID,PARENT,MAJ:MIN,FSROOT` adds about a mount, if those columns are present.
+///
+/// `findmnt` does not report peer groups, so `propagation` is left empty.
+fn mount_info(x: &mut IntermediateMap) -> Result<Option<MountInfo>> {
+    let (mount_id, parent_id, major_minor) =
+        match (x.remove("ID"), x.remove("PARENT"), x.remove("MAJ:MIN")) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => return Ok(None),
+        };
+
+    Ok(Some(MountInfo {
+        mount_id: parse_field("ID", mount_id)?,
+        parent_id: parse_field("PARENT", parent_id)?,
+        major_minor: MajorMinor(major_minor),
+        root: x.remove("FSROOT").unwrap_or_else(|| "/".into()).into(),
+        propagation: vec![],
+    }))
+}
+
+fn line_to_command(x: &[u8]) -> Result<MountCommand> {
+    let mut x: IntermediateMap = line_to_hashmap(x)?;
 
-    let (target, source, fstype, mount_opts, old_opts, old_target) = (
-        MountPoint(x.remove("TARGET").expect("missing TARGET").into()),
-        DevicePath(x.remove("SOURCE").expect("missing SOURCE").into()),
-        FsType(x.remove("FSTYPE").expect("missing FSTYPE")),
-        MountOpts(x.remove("OPTIONS").expect("missing OPTIONS")),
-        x.get("OLD-OPTIONS"),
-        x.get("OLD-TARGET"),
+    let (target, source, fstype, mount_opts) = (
+        MountPoint(required(&mut x, "TARGET")?.into()),
+        DevicePath(required(&mut x, "SOURCE")?.into()),
+        FsType(required(&mut x, "FSTYPE")?),
+        MountOpts(required(&mut x, "OPTIONS")?),
     );
 
-    match x.get("ACTION").map(AsRef::as_ref) {
-        Some("mount") | None => MountCommand::AddMount(target, source, fstype, mount_opts),
-        Some("umount") => MountCommand::RemoveMount(target, source, fstype, mount_opts),
+    let info = mount_info(&mut x)?;
+
+    let cmd = match x.get("ACTION").map(AsRef::as_ref) {
+        Some("mount") | None => MountCommand::AddMount(target, source, fstype, mount_opts, info),
+        Some("umount") => MountCommand::RemoveMount(target, source, fstype, mount_opts, info),
         Some("remount") => MountCommand::ReplaceMount(
             target,
             source,
             fstype,
             mount_opts,
-            MountOpts(old_opts.expect("missing OLD-OPTIONS").to_string()),
+            MountOpts(required(&mut x, "OLD-OPTIONS")?),
+            info,
         ),
         Some("move") => MountCommand::MoveMount(
             target,
             source,
             fstype,
             mount_opts,
-            MountPoint(old_target.expect("missing OLD-TARGET").into()),
+            MountPoint(required(&mut x, "OLD-TARGET")?.into()),
+            info,
         ),
-        Some(x) => {
-            eprintln!("Unexpected ACTION: {}", x);
-            exit(1);
-        }
-    }
+        Some(x) => return Err(Error::UnknownAction(x.to_string())),
+    };
+
+    Ok(cmd)
 }
 
 type IntermediateMap = HashMap<String, String>;
 
-fn line_to_hashmap(x: &[u8]) -> IntermediateMap {
-    str::from_utf8(x)
-        .expect("Did not convert from utf8")
-        .trim()
-        .split(' ')
-        .fold(HashMap::new(), build_hashmap)
+/// Reads a value up to the closing quote, or up to whitespace if it is unquoted.
+///
+/// `findmnt` writes unsafe bytes as `\xHH`, and a backslash before any other
+/// character stands for that character.
+fn parse_value(key: &str, xs: &mut Peekable<str::Chars>) -> Result<String> {
+    let quoted = xs.peek() == Some(&'"');
+
+    if quoted {
+        xs.next();
+    }
+
+    let mut out = vec![];
+
+    loop {
+        let c = match xs.next() {
+            Some('"') if quoted => break,
+            Some(c) if !quoted && c.is_whitespace() => break,
+            None if quoted => return Err(Error::UnterminatedValue(key.to_string())),
+            None => break,
+            Some(c) => c,
+        };
+
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+
+            continue;
+        }
+
+        match xs.next() {
+            Some('x') => {
+                let hex: String = xs.by_ref().take(2).collect();
+
+                let b = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2 && hex.chars().all(|x| x.is_ascii_hexdigit()))
+                    .ok_or_else(|| Error::InvalidEscape(key.to_string()))?;
+
+                out.push(b);
+            }
+            Some(c) => {
+                let mut buf = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            None => return Err(Error::InvalidEscape(key.to_string())),
+        }
+    }
+
+    Ok(str::from_utf8(&out)?.to_string())
 }
 
-fn build_hashmap(mut acc: IntermediateMap, x: &str) -> IntermediateMap {
-    let xs: Vec<&str> = x.splitn(2, '=').collect();
+/// Parses a line of `KEY="VALUE"` pairs, as written by `findmnt -P`.
+fn line_to_hashmap(x: &[u8]) -> Result<IntermediateMap> {
+    let mut xs = str::from_utf8(x)?.trim().chars().peekable();
+    let mut acc = HashMap::new();
 
-    let (k, v) = match xs.as_slice() {
-        [k, v] => (k, v.trim_matches('"')),
-        _ => panic!("Expected two matches from: {:?}", xs),
-    };
+    loop {
+        while xs.peek().map(|x| x.is_whitespace()).unwrap_or(false) {
+            xs.next();
+        }
+
+        if xs.peek().is_none() {
+            return Ok(acc);
+        }
 
-    acc.insert((*k).to_string(), v.to_string());
+        let mut key = String::new();
 
-    acc
+        loop {
+            match xs.peek() {
+                Some('=') => break,
+                Some(c) if !c.is_whitespace() => {
+                    key.push(*c);
+                    xs.next();
+                }
+                _ => return Err(Error::MissingEquals(key)),
+            }
+        }
+
+        xs.next();
+
+        let v = parse_value(&key, &mut xs)?;
+
+        acc.insert(key, v);
+    }
+}
+
+/// Wraps `xs` in a `Command::Batch`, unless there is only one of them.
+pub fn batch(mut xs: Vec<Command>) -> Option<Command> {
+    match xs.len() {
+        0 => None,
+        1 => xs.pop(),
+        _ => Some(Command::Batch(xs)),
+    }
 }
 
-/// Creates a stream that implements `AsyncWrite`
+/// The commands that bring the daemon from `old` to `new`.
+///
+/// Without `old`, as on a new connection, all local mounts are replaced,
+/// so mounts removed while disconnected do not linger in the daemon.
+pub fn mount_commands(
+    old: Option<&mountinfo::Snapshot>,
+    new: &mountinfo::Snapshot,
+) -> Vec<Command> {
+    match old {
+        Some(old) => mountinfo::diff(old, new)
+            .into_iter()
+            .map(Command::MountCommand)
+            .collect(),
+        None => vec![Command::MountCommand(MountCommand::SetMounts(
+            mountinfo::mounts(new),
+        ))],
+    }
+}
+
+/// Connects to the daemon.
 /// In this case, we use `tokio::net::UnixStream`,
 /// But we can substitute this fn for integration testing
-pub fn get_write_stream() -> impl AsyncWrite {
-    let stream = NetUnixStream::connect("/var/run/device-scanner.sock")
-        .expect("Unable to connect to device-scanner.sock");
+pub async fn get_write_stream() -> io::Result<UnixStream> {
+    UnixStream::connect(SOCKET_PATH).await
+}
+
+/// Writes a given buffer, handing back the writer for the next one.
+pub async fn write_all<A: AsyncWrite + Unpin>(mut a: A, buf: String) -> io::Result<A> {
+    a.write_all(buf.as_bytes()).await?;
 
-    UnixStream::from_std(stream, &Handle::default()).expect("Unable to consume device-scanner.sock")
+    Ok(a)
 }
 
-/// Writes a given buffer to the tokio runtime.
-pub fn write_all<A, T>(a: A, buf: T) -> impl Future<Item = (), Error = std::io::Error>
-where
-    A: AsyncWrite,
-    T: AsRef<[u8]>,
-{
-    tokio::io::write_all(a, buf).map(|_| ())
+/// Buffered stdin.
+pub fn stdin_to_file() -> impl AsyncBufRead + Unpin {
+    BufReader::new(tokio::io::stdin())
+}
+
+/// Waits for a line, then takes every line that follows it without waiting,
+/// so a burst of mount events turns into one batch.
+///
+/// A partial line is kept in `line` until the rest of it arrives.
+/// Nothing is returned at EOF.
+async fn read_ready_lines<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut xs = vec![];
+
+    let mut n = reader.read_until(b'\n', line).await?;
+
+    while n > 0 {
+        xs.push(line.split_off(0));
+
+        n = match reader.read_until(b'\n', line).now_or_never() {
+            Some(x) => x?,
+            None => return Ok(xs),
+        };
+    }
+
+    if !line.is_empty() {
+        xs.push(line.split_off(0));
+    }
+
+    Ok(xs)
 }
 
-/// convert stdin into a nonblocking file;
-/// this is the only part that makes use of tokio_file_unix
-pub fn stdin_to_file() -> impl AsyncRead + BufRead {
-    let file = tokio_file_unix::raw_stdin().unwrap();
-    let file = tokio_file_unix::File::new_nb(file).unwrap();
-    file.into_reader(&Handle::default()).unwrap()
+fn parse_line(line: &[u8]) -> Option<Command> {
+    match line_to_command(line) {
+        Ok(x) => Some(Command::MountCommand(x)),
+        Err(e) => {
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                tracing::warn!("Skipping line {:?}: {}", String::from_utf8_lossy(line), e);
+            }
+
+            None
+        }
+    }
+}
+
+/// Writes `x`, connecting first if there is no connection.
+///
+/// Connecting is retried with backoff, and `x` is written again
+/// on a new connection, after the same backoff, if the write fails.
+async fn send<W, W1, WF, W2, F>(mut conn: Option<W1>, x: &str, write_fn: &W, write_out: &W2) -> W1
+where
+    W: Fn() -> WF,
+    WF: Future<Output = io::Result<W1>>,
+    W2: Fn(W1, String) -> F,
+    F: Future<Output = io::Result<W1>>,
+{
+    let mut backoff = Backoff::default();
+
+    loop {
+        let c = match conn.take() {
+            Some(c) => c,
+            None => connect_with(Path::new(SOCKET_PATH), &mut backoff, write_fn).await,
+        };
+
+        match write_out(c, x.to_string()).await {
+            Ok(c) => return c,
+            Err(e) => {
+                tracing::warn!(
+                    "Could not write to {}: {}. Reconnecting in {:?}",
+                    SOCKET_PATH,
+                    e,
+                    backoff.duration()
+                );
+
+                backoff.wait().await;
+            }
+        }
+    }
 }
 
-/// Loops over lines streaming from STDIN.
+/// Loops over lines streaming from STDIN, sending them over one connection.
+///
+/// Lines that arrive together are sent as one `Command::Batch`.
 /// This has been extracted for integration
 /// testing purposes.
-pub fn looper<R, R1, W, W1, F, W2>(
+pub async fn looper<R, R1, W, W1, WF, W2, F>(
     read_fn: R,
     write_fn: W,
     write_out: W2,
-) -> impl Future<Item = (), Error = ()>
+) -> ClientResult<()>
 where
     R: Fn() -> R1,
-    R1: AsyncRead + BufRead + Send + 'static,
-    W: Fn() -> W1 + Send + 'static,
-    W1: AsyncWrite + Send + Sized + 'static,
-    F: Future<Item = (), Error = std::io::Error> + Send + 'static,
-    W2: Fn(W1, String) -> F + Send + Sync + 'static,
+    R1: AsyncBufRead + Unpin,
+    W: Fn() -> WF,
+    WF: Future<Output = io::Result<W1>>,
+    W2: Fn(W1, String) -> F,
+    F: Future<Output = io::Result<W1>>,
 {
-    let file = read_fn();
-
-    future::loop_fn(
-        (file, Vec::new(), write_fn, write_out),
-        |(file, line, write_fn, write_out)| {
-            // read each line
-            tokio::io::read_until(file, b'\n', line).and_then(|(file, mut line)| {
-                if str::from_utf8(&line).unwrap() == "" {
-                    return future::Either::A(future::done(Ok(future::Loop::Break(()))));
-                }
-
-                let mount_command: MountCommand = line_to_command(&line);
-
-                let x = serde_json::to_string(&Command::MountCommand(mount_command))
-                    .expect("Could not serialize mount command");
-
-                let s = write_fn();
+    let mut reader = read_fn();
+    let mut line = vec![];
+    let mut conn = None;
 
-                let wo = write_out(s, x);
+    loop {
+        let xs = read_ready_lines(&mut reader, &mut line).await?;
 
-                let next = if line.ends_with(b"\n") {
-                    line.clear();
-                    future::Loop::Continue((file, line, write_fn, write_out))
-                } else {
-                    future::Loop::Break(())
-                };
+        if xs.is_empty() {
+            return Ok(());
+        }
 
-                future::Either::B(wo.map(|_| next))
-            })
-        },
-    )
-    .map_err(|e| panic!("{:?}", e))
+        if let Some(x) = batch(xs.iter().filter_map(|x| parse_line(x)).collect()) {
+            conn = Some(send(conn, &encode(&x)?, &write_fn, &write_out).await);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -180,62 +409,262 @@ mod tests {
     fn test_line_to_hashmap_swap() {
         let line = b"TARGET=\"swap\" SOURCE=\"/dev/mapper/centos-swap\" FSTYPE=\"swap\" OPTIONS=\"defaults\"\n";
 
-        assert_debug_snapshot!(map_to_sorted_vec(line_to_hashmap(line)))
+        assert_debug_snapshot!(map_to_sorted_vec(line_to_hashmap(line).unwrap()))
     }
 
     #[test]
     fn test_line_to_hashmap_mount() {
         let line = b"ACTION=\"mount\" TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw,relatime,data=ordered\" OLD-TARGET=\"\" OLD-OPTIONS=\"\"\n";
 
-        assert_debug_snapshot!(map_to_sorted_vec(line_to_hashmap(line)))
+        assert_debug_snapshot!(map_to_sorted_vec(line_to_hashmap(line).unwrap()))
     }
 
     #[test]
     fn test_swap() {
         let line = b"TARGET=\"swap\" SOURCE=\"/dev/mapper/centos-swap\" FSTYPE=\"swap\" OPTIONS=\"defaults\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_poll_mount() {
         let line = b"ACTION=\"mount\" TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw,relatime,data=ordered\" OLD-TARGET=\"\" OLD-OPTIONS=\"\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_poll_umount() {
         let line = b"ACTION=\"umount\" TARGET=\"/testPool4\" SOURCE=\"testPool4\" FSTYPE=\"zfs\" OPTIONS=\"rw,xattr,noacl\" OLD-TARGET=\"\" OLD-OPTIONS=\"\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_poll_remount() {
         let line = b"ACTION=\"remount\" TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"ro,relatime,data=ordered\" OLD-TARGET=\"\" OLD-OPTIONS=\"rw,data=ordered\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_poll_move() {
         let line = b"ACTION=\"move\" TARGET=\"/mnt/part1a\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw,relatime,data=ordered\" OLD-TARGET=\"/mnt/part1\" OLD-OPTIONS=\"\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_list_mount() {
         let line = b"TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw,relatime,data=ordered\"\n";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
     }
 
     #[test]
     fn test_swap_extra() {
         let line = b"TARGET=\"swap\" SOURCE=\"/dev/mapper/VolGroup00-LogVol01\" FSTYPE=\"swap\" OPTIONS=\"defaults\"";
 
-        assert_debug_snapshot!(line_to_command(line))
+        assert_debug_snapshot!(line_to_command(line).unwrap())
+    }
+
+    #[test]
+    fn test_line_to_hashmap_escapes() {
+        let line = br#"TARGET="/mnt/my\x20disk" SOURCE="/dev/sdb1" FSTYPE="ext4" OPTIONS="rw" LABEL="a \"b\"" EMPTY="""#;
+
+        let x = line_to_hashmap(line).unwrap();
+
+        assert_eq!(x["TARGET"], "/mnt/my disk");
+        assert_eq!(x["LABEL"], "a \"b\"");
+        assert_eq!(x["EMPTY"], "");
+    }
+
+    #[test]
+    fn test_line_to_command_errors() {
+        let errs = vec![
+            &b"TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\""[..],
+            b"TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1",
+            b"TARGET=\"/mnt/\\x2\"",
+            b"TARGET \"/mnt/part1\"",
+            b"ACTION=\"bogus\" TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw\"",
+            b"ACTION=\"remount\" TARGET=\"/mnt/part1\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw\"",
+        ];
+
+        let xs: Vec<String> = errs
+            .into_iter()
+            .map(|x| line_to_command(x).unwrap_err().to_string())
+            .collect();
+
+        assert_eq!(
+            xs,
+            vec![
+                "Missing required field OPTIONS",
+                "Unterminated value for SOURCE",
+                "Invalid escape in value for TARGET",
+                "Expected KEY=\"VALUE\", found TARGET",
+                "Unknown ACTION bogus",
+                "Missing required field OLD-OPTIONS",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_to_command_mount_info() {
+        let line = b"TARGET=\"/srv/export\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw\" ID=\"76\" PARENT=\"41\" MAJ:MIN=\"8:65\" FSROOT=\"/export\"";
+
+        assert_eq!(
+            line_to_command(line).unwrap(),
+            MountCommand::AddMount(
+                MountPoint("/srv/export".into()),
+                DevicePath("/dev/sde1".into()),
+                FsType("ext4".into()),
+                MountOpts("rw".into()),
+                Some(MountInfo {
+                    mount_id: 76,
+                    parent_id: 41,
+                    major_minor: MajorMinor("8:65".into()),
+                    root: "/export".into(),
+                    propagation: vec![],
+                })
+            )
+        );
+
+        let line = b"TARGET=\"/srv/export\" SOURCE=\"/dev/sde1\" FSTYPE=\"ext4\" OPTIONS=\"rw\" ID=\"x\" PARENT=\"41\" MAJ:MIN=\"8:65\"";
+
+        assert_eq!(
+            line_to_command(line).unwrap_err().to_string(),
+            "Could not parse ID=x"
+        );
+    }
+
+    #[test]
+    fn test_mount_commands_after_reconnect() {
+        use device_types::mount::Mount;
+
+        let swaps = "Filename Type Size Used Priority\n";
+        let mountinfo = "\
+75 41 8:65 / /mnt/part1 rw,relatime shared:35 - ext4 /dev/sde1 rw,data=ordered
+76 41 8:66 / /mnt/part2 rw,relatime shared:36 - ext4 /dev/sde2 rw,data=ordered
+";
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        std::fs::create_dir_all(root.join("self")).unwrap();
+        std::fs::write(root.join("self/mountinfo"), mountinfo).unwrap();
+        std::fs::write(root.join("swaps"), swaps).unwrap();
+
+        let watcher = mountinfo::Watcher::new(root).unwrap();
+
+        let old = watcher.snapshot().unwrap();
+
+        // /mnt/part2 is unmounted while the daemon is unreachable.
+        std::fs::write(
+            root.join("self/mountinfo"),
+            &mountinfo[..mountinfo.find("76 ").unwrap()],
+        )
+        .unwrap();
+
+        let new = watcher.snapshot().unwrap();
+
+        let targets = |xs: Vec<Command>| -> Vec<Vec<Mount>> {
+            xs.into_iter()
+                .filter_map(|x| match x {
+                    Command::MountCommand(MountCommand::SetMounts(xs)) => Some(xs),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(targets(mount_commands(None, &old)).concat().len(), 2);
+
+        // Once reconnected, the daemon is given every mount again, not just the additions.
+        match &targets(mount_commands(None, &new))[..] {
+            [xs] => assert_eq!(
+                xs.iter().map(|x| &x.target).collect::<Vec<_>>(),
+                vec![&MountPoint("/mnt/part1".into())]
+            ),
+            xs => panic!("Expected a single SetMounts, got {:?}", xs),
+        }
+
+        assert_eq!(
+            mount_commands(Some(&old), &new),
+            vec![Command::MountCommand(MountCommand::RemoveMount(
+                MountPoint("/mnt/part2".into()),
+                DevicePath("/dev/sde2".into()),
+                FsType("ext4".into()),
+                MountOpts("rw,relatime,data=ordered".into()),
+                old.mounts[&76].info.clone().into(),
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_reconnects() {
+        use futures::future;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let attempts = AtomicUsize::new(0);
+
+        let write_fn = || {
+            future::ready(if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+            } else {
+                Ok(vec![])
+            })
+        };
+
+        let conn = send(None, "x\n", &write_fn, &write_all).await;
+
+        assert_eq!(conn, b"x\n".to_vec());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_after_write_error() {
+        use futures::future;
+        use std::{
+            sync::atomic::{AtomicUsize, Ordering},
+            time::Instant,
+        };
+
+        let writes = AtomicUsize::new(0);
+
+        let write_out = |s: Vec<u8>, x: String| {
+            future::ready(if writes.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            } else {
+                Ok([s, x.into_bytes()].concat())
+            })
+        };
+
+        let start = Instant::now();
+
+        let conn = send(
+            Some(vec![]),
+            "x\n",
+            &|| future::ready(Ok(vec![])),
+            &write_out,
+        )
+        .await;
+
+        assert_eq!(conn, b"x\n".to_vec());
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= device_scanner_client::INITIAL_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_read_ready_lines() {
+        let mut reader: &[u8] = b"a\nb\nc";
+        let mut line = vec![];
+
+        assert_eq!(
+            read_ready_lines(&mut reader, &mut line).await.unwrap(),
+            vec![b"a\n".to_vec(), b"b\n".to_vec(), b"c".to_vec()]
+        );
+        assert!(read_ready_lines(&mut reader, &mut line)
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
@@ -0,0 +1,531 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Watches `/proc/self/mountinfo` and `/proc/swaps` directly.
+//!
+//! The kernel flags `mountinfo` with `POLLPRI` whenever the mount table changes.
+//! `/proc/swaps` cannot be polled, so it is re-read whenever the wait times out.
+//! Each read is a `Snapshot`, and `diff` turns two of them into `MountCommand`s.
+//! `mounts` lists a single one in full, for a new connection.
+
+use device_types::{
+    mount::{
+        FsType, MajorMinor, Mount, MountCommand, MountInfo, MountOpts, MountPoint, Propagation,
+    },
+    DevicePath,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// A single line of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub target: MountPoint,
+    pub source: DevicePath,
+    pub fs_type: FsType,
+    /// The per mount options, followed by any superblock options not already present,
+    /// in the same form `findmnt` reports them.
+    pub opts: MountOpts,
+    pub info: MountInfo,
+}
+
+/// The mount table and active swaps at one point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Keyed by `mount_id`.
+    pub mounts: BTreeMap<u32, Entry>,
+    pub swaps: BTreeSet<DevicePath>,
+}
+
+/// Reverses the octal escaping the kernel applies to spaces, tabs, newlines and backslashes.
+///
+/// `/etc/fstab` uses the same escaping.
+pub(crate) fn unescape(x: &str) -> String {
+    let bs = x.as_bytes();
+    let mut out = Vec::with_capacity(bs.len());
+    let mut i = 0;
+
+    while i < bs.len() {
+        if bs[i] == b'\\' && i + 4 <= bs.len() {
+            let digits = &bs[i + 1..i + 4];
+
+            if digits.iter().all(|d| b'0' <= *d && *d <= b'7') {
+                out.push(digits.iter().fold(0u8, |acc, d| (acc << 3) | (d - b'0')));
+                i += 4;
+                continue;
+            }
+        }
+
+        out.push(bs[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn merge_opts(mount_opts: &str, super_opts: &str) -> MountOpts {
+    let mount_opts = MountOpts(mount_opts.to_string());
+    let super_opts = MountOpts(super_opts.to_string());
+
+    let mut xs = mount_opts.pairs();
+
+    for x in super_opts.pairs() {
+        // The per mount `ro` / `rw` is the one in effect.
+        if x.0 != "ro" && x.0 != "rw" && !xs.contains(&x) {
+            xs.push(x);
+        }
+    }
+
+    let xs: Vec<String> = xs
+        .into_iter()
+        .map(|(k, v)| match v {
+            Some(v) => format!("{}={}", k, v),
+            None => k.to_string(),
+        })
+        .collect();
+
+    MountOpts(xs.join(","))
+}
+
+fn parse_propagation(x: &str) -> Option<Propagation> {
+    let mut xs = x.splitn(2, ':');
+
+    let tag = xs.next()?;
+    let group = xs.next().and_then(|x| x.parse().ok());
+
+    match (tag, group) {
+        ("shared", Some(x)) => Some(Propagation::Shared(x)),
+        ("master", Some(x)) => Some(Propagation::Master(x)),
+        ("propagate_from", Some(x)) => Some(Propagation::PropagateFrom(x)),
+        ("unbindable", None) => Some(Propagation::Unbindable),
+        _ => None,
+    }
+}
+
+fn parse_mountinfo_line(line: &str) -> Option<Entry> {
+    let mut xs = line.split(' ');
+
+    let mount_id = xs.next()?.parse().ok()?;
+    let parent_id = xs.next()?.parse().ok()?;
+    let major_minor = MajorMinor(xs.next()?.to_string());
+    let root = unescape(xs.next()?).into();
+    let target = MountPoint(unescape(xs.next()?).into());
+    let mount_opts = xs.next()?;
+
+    // The optional fields end with a lone `-`. Unknown ones are skipped.
+    let propagation = xs
+        .by_ref()
+        .take_while(|x| *x != "-")
+        .filter_map(parse_propagation)
+        .collect();
+
+    let fs_type = FsType(unescape(xs.next()?));
+    let source = DevicePath(unescape(xs.next()?).into());
+    let super_opts = xs.next()?;
+
+    Some(Entry {
+        target,
+        source,
+        fs_type,
+        opts: merge_opts(mount_opts, super_opts),
+        info: MountInfo {
+            mount_id,
+            parent_id,
+            major_minor,
+            root,
+            propagation,
+        },
+    })
+}
+
+/// Parses the contents of `/proc/self/mountinfo`. Malformed lines are logged and skipped.
+pub fn parse_mountinfo(x: &str) -> BTreeMap<u32, Entry> {
+    x.lines()
+        .filter(|x| !x.trim().is_empty())
+        .filter_map(|x| match parse_mountinfo_line(x) {
+            Some(m) => Some((m.info.mount_id, m)),
+            None => {
+                tracing::warn!("Skipping malformed mountinfo line: {:?}", x);
+
+                None
+            }
+        })
+        .collect()
+}
+
+/// Parses the contents of `/proc/swaps`, returning the active swap devices and files.
+pub fn parse_swaps(x: &str) -> BTreeSet<DevicePath> {
+    x.lines()
+        .skip(1)
+        .filter_map(|x| x.split_whitespace().next())
+        .map(|x| DevicePath(unescape(x).into()))
+        .collect()
+}
+
+fn swap(source: &DevicePath) -> (MountPoint, DevicePath, FsType, MountOpts) {
+    (
+        MountPoint("swap".into()),
+        source.clone(),
+        FsType("swap".into()),
+        MountOpts("defaults".into()),
+    )
+}
+
+fn swap_command(add: bool, source: &DevicePath) -> MountCommand {
+    let (target, source, fs_type, opts) = swap(source);
+
+    if add {
+        MountCommand::AddMount(target, source, fs_type, opts, None)
+    } else {
+        MountCommand::RemoveMount(target, source, fs_type, opts, None)
+    }
+}
+
+fn add(x: &Entry) -> MountCommand {
+    MountCommand::AddMount(
+        x.target.clone(),
+        x.source.clone(),
+        x.fs_type.clone(),
+        x.opts.clone(),
+        Some(x.info.clone()),
+    )
+}
+
+fn remove(x: &Entry) -> MountCommand {
+    MountCommand::RemoveMount(
+        x.target.clone(),
+        x.source.clone(),
+        x.fs_type.clone(),
+        x.opts.clone(),
+        Some(x.info.clone()),
+    )
+}
+
+/// Every mount and swap in `x`, as the daemon keeps them.
+pub fn mounts(x: &Snapshot) -> Vec<Mount> {
+    let swaps = x.swaps.iter().map(|x| {
+        let (target, source, fs_type, opts) = swap(x);
+
+        Mount::new(target, source, fs_type, opts)
+    });
+
+    x.mounts
+        .values()
+        .map(|x| Mount {
+            info: Some(x.info.clone()),
+            ..Mount::new(
+                x.target.clone(),
+                x.source.clone(),
+                x.fs_type.clone(),
+                x.opts.clone(),
+            )
+        })
+        .chain(swaps)
+        .collect()
+}
+
+/// Whether `x` and `y` are the same mount, as far as a diff is concerned.
+fn same_mount(x: &Entry, y: &Entry) -> bool {
+    // Mount ids are reused, so the id alone is not enough.
+    x.source == y.source && x.fs_type == y.fs_type
+}
+
+/// The `MountCommand`s that take `old` to `new`.
+///
+/// Mounts are matched by mount id, so a mount that changed target is a move
+/// and one that changed options, parent or propagation is a remount. Removals come first, deepest mounts
+/// first, and additions last, in mount id order.
+pub fn diff(old: &Snapshot, new: &Snapshot) -> Vec<MountCommand> {
+    let mut xs = vec![];
+
+    for (id, x) in old.mounts.iter().rev() {
+        match new.mounts.get(id) {
+            Some(y) if same_mount(x, y) => {}
+            _ => xs.push(remove(x)),
+        }
+    }
+
+    xs.extend(
+        old.swaps
+            .difference(&new.swaps)
+            .map(|x| swap_command(false, x)),
+    );
+
+    for (id, y) in new.mounts.iter() {
+        let x = match old.mounts.get(id) {
+            Some(x) if same_mount(x, y) => x,
+            _ => continue,
+        };
+
+        if x.target != y.target {
+            xs.push(MountCommand::MoveMount(
+                y.target.clone(),
+                x.source.clone(),
+                x.fs_type.clone(),
+                x.opts.clone(),
+                x.target.clone(),
+                Some(y.info.clone()),
+            ));
+        }
+
+        // A move already carries the new `info`.
+        if x.opts != y.opts || (x.target == y.target && x.info != y.info) {
+            xs.push(MountCommand::ReplaceMount(
+                y.target.clone(),
+                y.source.clone(),
+                y.fs_type.clone(),
+                y.opts.clone(),
+                x.opts.clone(),
+                Some(y.info.clone()),
+            ));
+        }
+    }
+
+    xs.extend(
+        new.mounts
+            .iter()
+            .filter(|(id, y)| match old.mounts.get(id) {
+                Some(x) => !same_mount(x, y),
+                None => true,
+            })
+            .map(|(_, y)| add(y)),
+    );
+
+    xs.extend(
+        new.swaps
+            .difference(&old.swaps)
+            .map(|x| swap_command(true, x)),
+    );
+
+    xs
+}
+
+/// Reads and waits on the mount table under a `proc` root.
+pub struct Watcher {
+    proc: PathBuf,
+    mountinfo: File,
+}
+
+impl Watcher {
+    /// `proc` is the procfs root, so a fake tree can be used in tests.
+    pub fn new(proc: impl Into<PathBuf>) -> io::Result<Self> {
+        let proc = proc.into();
+        let mountinfo = File::open(proc.join("self/mountinfo"))?;
+
+        Ok(Watcher { proc, mountinfo })
+    }
+
+    pub fn snapshot(&self) -> io::Result<Snapshot> {
+        let mounts = parse_mountinfo(&fs::read_to_string(self.proc.join("self/mountinfo"))?);
+        let swaps = parse_swaps(&fs::read_to_string(self.proc.join("swaps"))?);
+
+        Ok(Snapshot { mounts, swaps })
+    }
+
+    /// Blocks until the mount table changes or `timeout` passes.
+    ///
+    /// Returns whether the mount table changed.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.mountinfo.as_raw_fd(),
+            events: libc::POLLPRI,
+            revents: 0,
+        };
+
+        let n = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+
+        match n {
+            -1 => {
+                let e = io::Error::last_os_error();
+
+                if e.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(fd.revents & (libc::POLLPRI | libc::POLLERR) != 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+18 41 0:17 / /sys rw,nosuid,nodev,noexec,relatime shared:6 - sysfs sysfs rw
+41 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/centos-root rw,attr2,inode64,noquota
+75 41 8:65 / /mnt/part1 rw,relatime shared:35 - ext4 /dev/sde1 rw,data=ordered
+80 41 0:45 / /testPool4 rw,xattr,noacl shared:40 - zfs testPool4 rw,xattr,noacl
+";
+
+    fn info(mount_id: u32, major_minor: &str, shared: u32) -> Option<MountInfo> {
+        Some(MountInfo {
+            mount_id,
+            parent_id: 41,
+            major_minor: MajorMinor(major_minor.into()),
+            root: "/".into(),
+            propagation: vec![Propagation::Shared(shared)],
+        })
+    }
+
+    fn snapshot(mountinfo: &str, swaps: &str) -> Snapshot {
+        Snapshot {
+            mounts: parse_mountinfo(mountinfo),
+            swaps: parse_swaps(swaps),
+        }
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let xs = parse_mountinfo(&format!(
+            "{}not a mountinfo line\n90 41 8:66 /data /mnt/with\\040space ro,relatime master:3 propagate_from:2 - ext4 /dev/sde2 rw\n",
+            MOUNTINFO
+        ));
+
+        assert_eq!(xs.len(), 5);
+
+        assert_eq!(
+            xs[&75],
+            Entry {
+                target: MountPoint("/mnt/part1".into()),
+                source: DevicePath("/dev/sde1".into()),
+                fs_type: FsType("ext4".into()),
+                opts: MountOpts("rw,relatime,data=ordered".into()),
+                info: info(75, "8:65", 35).unwrap(),
+            }
+        );
+
+        assert_eq!(xs[&90].target, MountPoint("/mnt/with space".into()));
+        assert_eq!(xs[&90].info.root, PathBuf::from("/data"));
+        assert_eq!(
+            xs[&90].info.propagation,
+            vec![Propagation::Master(3), Propagation::PropagateFrom(2)]
+        );
+        assert_eq!(xs[&90].opts, MountOpts("ro,relatime".into()));
+    }
+
+    #[test]
+    fn test_parse_swaps() {
+        let xs = parse_swaps(
+            "Filename\t\t\t\tType\t\tSize\tUsed\tPriority\n/dev/dm-1                               partition\t2097148\t0\t-2\n",
+        );
+
+        assert_eq!(
+            xs.into_iter().collect::<Vec<_>>(),
+            vec![DevicePath("/dev/dm-1".into())]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let swaps = "Filename Type Size Used Priority\n/dev/dm-1 partition 2097148 0 -2\n";
+
+        let old = snapshot(MOUNTINFO, swaps);
+
+        assert_eq!(diff(&old, &old), vec![]);
+
+        let new = snapshot(
+            &MOUNTINFO
+                .replace("/ /mnt/part1 rw,", "/ /mnt/part1a ro,")
+                .replace(
+                "80 41 0:45 / /testPool4 rw,xattr,noacl shared:40 - zfs testPool4 rw,xattr,noacl\n",
+                "81 41 0:46 / /mnt/lustre rw shared:41 - lustre 10.0.0.1@tcp:/fs rw,flock\n",
+            ),
+            "Filename Type Size Used Priority\n",
+        );
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                MountCommand::RemoveMount(
+                    MountPoint("/testPool4".into()),
+                    DevicePath("testPool4".into()),
+                    FsType("zfs".into()),
+                    MountOpts("rw,xattr,noacl".into()),
+                    info(80, "0:45", 40),
+                ),
+                MountCommand::RemoveMount(
+                    MountPoint("swap".into()),
+                    DevicePath("/dev/dm-1".into()),
+                    FsType("swap".into()),
+                    MountOpts("defaults".into()),
+                    None,
+                ),
+                MountCommand::MoveMount(
+                    MountPoint("/mnt/part1a".into()),
+                    DevicePath("/dev/sde1".into()),
+                    FsType("ext4".into()),
+                    MountOpts("rw,relatime,data=ordered".into()),
+                    MountPoint("/mnt/part1".into()),
+                    info(75, "8:65", 35),
+                ),
+                MountCommand::ReplaceMount(
+                    MountPoint("/mnt/part1a".into()),
+                    DevicePath("/dev/sde1".into()),
+                    FsType("ext4".into()),
+                    MountOpts("ro,relatime,data=ordered".into()),
+                    MountOpts("rw,relatime,data=ordered".into()),
+                    info(75, "8:65", 35),
+                ),
+                MountCommand::AddMount(
+                    MountPoint("/mnt/lustre".into()),
+                    DevicePath("10.0.0.1@tcp:/fs".into()),
+                    FsType("lustre".into()),
+                    MountOpts("rw,flock".into()),
+                    info(81, "0:46", 41),
+                ),
+            ]
+        );
+
+        // Making a mount private is a remount with the same options.
+        let private = snapshot(&MOUNTINFO.replace(" shared:35 ", " "), swaps);
+
+        assert_eq!(
+            diff(&old, &private),
+            vec![MountCommand::ReplaceMount(
+                MountPoint("/mnt/part1".into()),
+                DevicePath("/dev/sde1".into()),
+                FsType("ext4".into()),
+                MountOpts("rw,relatime,data=ordered".into()),
+                MountOpts("rw,relatime,data=ordered".into()),
+                Some(MountInfo {
+                    propagation: vec![],
+                    ..info(75, "8:65", 35).unwrap()
+                }),
+            )]
+        );
+
+        // Starting from nothing adds everything, swaps included.
+        assert_eq!(diff(&Snapshot::default(), &old).len(), 5);
+    }
+
+    #[test]
+    fn test_watcher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        fs::create_dir_all(root.join("self")).unwrap();
+        fs::write(root.join("self/mountinfo"), MOUNTINFO).unwrap();
+        fs::write(root.join("swaps"), "Filename Type Size Used Priority\n").unwrap();
+
+        let watcher = Watcher::new(root).unwrap();
+
+        let x = watcher.snapshot().unwrap();
+
+        assert_eq!(x.mounts.len(), 4);
+        assert!(x.swaps.is_empty());
+
+        // A regular file never raises POLLPRI.
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Fixtures shared by the tests and benches of the workspace.
+
+use device_types::{
+    mount::{FsType, MountCommand, MountOpts, MountPoint},
+    uevent::UEvent,
+    Command,
+};
+use im::{ordset, vector};
+
+/// A 10GiB disk at `/dev/<name>`, with nothing on it.
+pub fn uevent(name: &str, major: &str, minor: &str) -> UEvent {
+    UEvent {
+        major: major.to_string(),
+        minor: minor.to_string(),
+        seqnum: 1,
+        paths: ordset![format!("/dev/{}", name).into()],
+        devname: format!("/dev/{}", name).into(),
+        devpath: format!("/devices/virtual/block/{}", name).into(),
+        devtype: "disk".to_string(),
+        vendor: None,
+        model: None,
+        serial: None,
+        fs_type: None,
+        fs_usage: None,
+        fs_uuid: None,
+        fs_label: None,
+        part_entry_number: None,
+        part_entry_mm: None,
+        size: Some(10_737_418_240),
+        rotational: None,
+        scsi80: None,
+        scsi83: None,
+        read_only: Some(false),
+        bios_boot: None,
+        zfs_reserved: None,
+        is_mpath: None,
+        dm_slave_mms: vector![],
+        dm_vg_size: None,
+        md_devs: ordset![],
+        dm_multipath_devpath: None,
+        dm_name: None,
+        dm_lv_name: None,
+        lv_uuid: None,
+        dm_vg_name: None,
+        vg_uuid: None,
+        md_uuid: None,
+    }
+}
+
+/// An online pool with a disk vdev for each of `dev_ids`, the names under
+/// `/dev/disk/by-id` the disks were added by, and no datasets.
+pub fn pool(name: &str, guid: u64, dev_ids: &[&str]) -> libzfs_types::Pool {
+    libzfs_types::Pool {
+        name: name.to_string(),
+        guid,
+        health: "ONLINE".to_string(),
+        hostname: "mds1".to_string(),
+        hostid: None,
+        state: "ACTIVE".to_string(),
+        readonly: false,
+        size: "10737418240".to_string(),
+        vdev: libzfs_types::VDev::Root {
+            children: dev_ids
+                .iter()
+                .map(|x| libzfs_types::VDev::Disk {
+                    guid: None,
+                    state: "ONLINE".to_string(),
+                    path: format!("/dev/disk/by-id/{}", x).into(),
+                    dev_id: Some(x.to_string()),
+                    phys_path: None,
+                    whole_disk: Some(false),
+                    is_log: None,
+                })
+                .collect(),
+            spares: vec![],
+            cache: vec![],
+        },
+        props: vec![],
+        datasets: vec![],
+    }
+}
+
+/// Mounts `/dev/sde1` at `target`.
+pub fn mount_cmd(target: &str) -> Command {
+    Command::MountCommand(MountCommand::AddMount(
+        MountPoint(target.into()),
+        "/dev/sde1".into(),
+        FsType("ext4".to_string()),
+        MountOpts("rw".to_string()),
+        None,
+    ))
+}
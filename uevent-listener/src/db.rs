@@ -0,0 +1,209 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Enumerates existing block devices from sysfs and the udev database.
+//!
+//! This replaces `udevadm trigger` for initial population: the properties
+//! udev stored for each device are read back without re-running any rules.
+
+use crate::Props;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Parses `KEY=VALUE` lines, as found in sysfs `uevent` files.
+fn parse_uevent_file(x: &str) -> Props {
+    x.lines()
+        .filter_map(|l| {
+            let mut xs = l.splitn(2, '=');
+
+            match (xs.next(), xs.next()) {
+                (Some(k), Some(v)) => Some((k.to_string(), v.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parses a udev database entry.
+///
+/// `E:` lines hold properties and `S:` lines hold symlinks relative to `/dev`.
+fn parse_db(x: &str) -> Props {
+    let mut props = Props::new();
+    let mut links = vec![];
+
+    for l in x.lines().filter(|l| l.len() > 2) {
+        match l.split_at(2) {
+            ("E:", x) => props.extend(parse_uevent_file(x)),
+            ("S:", x) => links.push(format!("/dev/{}", x)),
+            _ => {}
+        }
+    }
+
+    if !links.is_empty() {
+        props.insert("DEVLINKS".into(), links.join(" "));
+    }
+
+    props
+}
+
+/// Turns a path under `sys` into a `DEVPATH`, or `None` if it lies outside `sys`.
+fn to_devpath(sys: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let real = fs::canonicalize(path)?;
+
+    match real.strip_prefix(fs::canonicalize(sys)?) {
+        Ok(x) => Ok(Some(Path::new("/").join(x))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Builds the properties a uevent for the block device at `path` would carry.
+///
+/// Returns `None` when udev has not processed the device yet;
+/// it will be picked up by the event udev emits once it has.
+fn device_props(sys: &Path, run: &Path, path: &Path) -> io::Result<Option<Props>> {
+    let devpath = match to_devpath(sys, path)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let mut props = parse_uevent_file(&fs::read_to_string(path.join("uevent"))?);
+
+    let (major, minor) = match (props.get("MAJOR"), props.get("MINOR")) {
+        (Some(major), Some(minor)) => (major.clone(), minor.clone()),
+        _ => return Ok(None),
+    };
+
+    let db = match fs::read_to_string(run.join(format!("udev/data/b{}:{}", major, minor))) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    props.extend(parse_db(&db));
+
+    if let Some(x) = props.get("DEVNAME").cloned() {
+        props.insert("DEVNAME".into(), format!("/dev/{}", x));
+    }
+
+    props.insert("DEVPATH".into(), devpath.to_string_lossy().to_string());
+    props.insert("SUBSYSTEM".into(), "block".into());
+    props.insert("ACTION".into(), "add".into());
+
+    Ok(Some(props))
+}
+
+fn block_paths(sys: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(sys.join("class/block"))?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<io::Result<_>>()?;
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// The `DEVPATH` of every block device in sysfs, whether or not udev has processed it.
+pub fn devpaths(sys: &Path) -> io::Result<HashSet<PathBuf>> {
+    let xs = block_paths(sys)?
+        .iter()
+        .filter_map(|p| match to_devpath(sys, p) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::debug!("Could not resolve {:?}: {}", p, e);
+
+                None
+            }
+        })
+        .collect();
+
+    Ok(xs)
+}
+
+/// Reads the properties of every block device udev knows about.
+///
+/// `SEQNUM` is left for the caller to set, see `scan::Scanner`.
+///
+/// `sys` and `run` are the sysfs and `/run` roots, so a fake tree can be used in tests.
+pub fn enumerate(sys: &Path, run: &Path) -> io::Result<Vec<Props>> {
+    let xs = block_paths(sys)?
+        .iter()
+        .filter_map(|p| match device_props(sys, run, p) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!("Could not read {:?}: {}", p, e);
+
+                None
+            }
+        })
+        .collect();
+
+    Ok(xs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn write(p: &Path, x: &str) {
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, x).unwrap();
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        let sys = root.join("sys");
+        let run = root.join("run");
+
+        let sda =
+            sys.join("devices/pci0000:00/0000:00:0d.0/ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        let sdb =
+            sys.join("devices/pci0000:00/0000:00:0d.0/ata2/host1/target1:0:0/1:0:0:0/block/sdb");
+
+        write(
+            &sda.join("uevent"),
+            "MAJOR=8\nMINOR=0\nDEVNAME=sda\nDEVTYPE=disk\n",
+        );
+        write(
+            &sdb.join("uevent"),
+            "MAJOR=8\nMINOR=16\nDEVNAME=sdb\nDEVTYPE=disk\n",
+        );
+
+        fs::create_dir_all(sys.join("class/block")).unwrap();
+        symlink(&sda, sys.join("class/block/sda")).unwrap();
+        symlink(&sdb, sys.join("class/block/sdb")).unwrap();
+
+        // sdb has not been processed by udev yet.
+        write(
+            &run.join("udev/data/b8:0"),
+            "S:disk/by-id/ata-VBOX_HARDDISK_VB1c9a3c1c-bd0a2fd4\nI:1570000000\nE:ID_SERIAL=VBOX_HARDDISK_VB1c9a3c1c-bd0a2fd4\nE:IML_SIZE=41943040\n",
+        );
+
+        let xs = enumerate(&sys, &run).unwrap();
+
+        assert_eq!(xs.len(), 1);
+
+        let x = &xs[0];
+
+        assert_eq!(
+            x["DEVPATH"],
+            "/devices/pci0000:00/0000:00:0d.0/ata1/host0/target0:0:0/0:0:0:0/block/sda"
+        );
+        assert_eq!(x["DEVNAME"], "/dev/sda");
+        assert_eq!(
+            x["DEVLINKS"],
+            "/dev/disk/by-id/ata-VBOX_HARDDISK_VB1c9a3c1c-bd0a2fd4"
+        );
+        assert_eq!(x["IML_SIZE"], "41943040");
+        assert_eq!(x["ACTION"], "add");
+
+        assert_eq!(devpaths(&sys).unwrap().len(), 2);
+    }
+}
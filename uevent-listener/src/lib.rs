@@ -0,0 +1,385 @@
+// Copyright (c) 2018 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Turns udev properties into `UdevCommand`s.
+//!
+//! Properties come from the environment when run by the udev rule,
+//! or from the [`netlink`](netlink/index.html) monitor and the
+//! [`udev database`](db/index.html) when run with `--daemon`.
+//! SCSI identifiers, read-only state and dm slaves are read from
+//! [`sysfs`](sysfs/index.html).
+
+#[cfg(test)]
+#[macro_use]
+extern crate pretty_assertions;
+
+pub mod db;
+pub mod netlink;
+pub mod scan;
+pub mod sysfs;
+
+use device_types::{udev::UdevCommand, uevent::UEvent, DevicePath};
+use im::{OrdSet, Vector};
+use std::{
+    collections::HashMap,
+    convert::Into,
+    error, fmt, io,
+    path::{Path, PathBuf},
+    result,
+    string::ToString,
+};
+use sysfs::Sysfs;
+
+/// The properties of a single uevent, keyed by name.
+pub type Props = HashMap<String, String>;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    ClientError(device_scanner_client::Error),
+    MissingField(String),
+    InvalidField(String, String),
+    UnknownAction(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::ClientError(ref err) => write!(f, "{}", err),
+            Error::MissingField(ref x) => write!(f, "Missing required field {}", x),
+            Error::InvalidField(ref x, ref v) => write!(f, "Could not parse {}={}", x, v),
+            Error::UnknownAction(ref x) => write!(f, "Unknown ACTION {}", x),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::ClientError(ref err) => Some(err),
+            Error::MissingField(_) | Error::InvalidField(_, _) | Error::UnknownAction(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<device_scanner_client::Error> for Error {
+    fn from(err: device_scanner_client::Error) -> Self {
+        Error::ClientError(err)
+    }
+}
+
+fn required_field(props: &Props, name: &str) -> Result<String> {
+    props
+        .get(name)
+        .cloned()
+        .ok_or_else(|| Error::MissingField(name.to_string()))
+}
+
+fn optional_field(props: &Props, name: &str) -> Option<String> {
+    props.get(name).cloned()
+}
+
+fn split_space(x: &str) -> Vector<String> {
+    x.split(' ')
+        .filter(|x| x.trim() != "")
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn get_paths(props: &Props) -> Result<OrdSet<DevicePath>> {
+    let devlinks = optional_field(props, "DEVLINKS").unwrap_or_default();
+
+    let devname = required_field(props, "DEVNAME")?;
+
+    let mut xs: OrdSet<DevicePath> = split_space(&devlinks)
+        .iter()
+        .map(Into::into)
+        .map(DevicePath)
+        .collect();
+
+    xs.insert(DevicePath(devname.into()));
+
+    Ok(xs)
+}
+
+fn empty_str_to_none(x: String) -> Option<String> {
+    match x.as_str() {
+        "" => None,
+        y => Some(y.to_string()),
+    }
+}
+
+fn parse_to<T: std::str::FromStr>(x: String) -> Option<T> {
+    x.parse::<T>().ok()
+}
+
+fn is_one(x: String) -> bool {
+    x == "1"
+}
+
+fn lvm_uuids(x: String) -> Option<(String, String)> {
+    let lvm_pfix = "LVM-";
+    let uuid_len = 32;
+
+    if !x.starts_with(lvm_pfix) {
+        None
+    } else {
+        let uuids = x.get(lvm_pfix.len()..)?;
+
+        if uuids.len() != (uuid_len * 2) {
+            None
+        } else {
+            Some((
+                uuids.get(0..uuid_len)?.to_string(),
+                uuids.get(uuid_len..)?.to_string(),
+            ))
+        }
+    }
+}
+
+fn md_devs<I>(iter: I) -> OrdSet<DevicePath>
+where
+    I: Iterator<Item = (String, String)>,
+{
+    iter.filter(|(key, _)| key.starts_with("MD_DEVICE_"))
+        .filter(|(key, _)| key.ends_with("_DEV"))
+        .map(|(_, v)| v.into())
+        .map(DevicePath)
+        .collect()
+}
+
+/// Builds a `UEvent` from `props`.
+///
+/// Values that used to be computed by the udev rule are read from `sysfs`,
+/// falling back to the `IML_*` properties when the device is gone (i.e. on `remove`).
+pub fn build_uevent(props: &Props, sysfs: &Sysfs) -> Result<UEvent> {
+    let devname = required_field(props, "DEVNAME")?.into();
+    let devpath: PathBuf = required_field(props, "DEVPATH")?.into();
+
+    let seqnum = required_field(props, "SEQNUM")?;
+
+    Ok(UEvent {
+        major: required_field(props, "MAJOR")?,
+        minor: required_field(props, "MINOR")?,
+        seqnum: parse_to(seqnum.clone())
+            .ok_or_else(|| Error::InvalidField("SEQNUM".to_string(), seqnum))?,
+        paths: get_paths(props)?,
+        devname,
+        devpath: devpath.clone(),
+        devtype: required_field(props, "DEVTYPE")?,
+        vendor: optional_field(props, "ID_VENDOR"),
+        model: optional_field(props, "ID_MODEL"),
+        serial: optional_field(props, "ID_SERIAL"),
+        fs_type: optional_field(props, "ID_FS_TYPE").and_then(empty_str_to_none),
+        fs_usage: optional_field(props, "ID_FS_USAGE").and_then(empty_str_to_none),
+        fs_uuid: optional_field(props, "ID_FS_UUID").and_then(empty_str_to_none),
+        fs_label: optional_field(props, "ID_FS_LABEL").and_then(empty_str_to_none),
+        part_entry_number: optional_field(props, "ID_PART_ENTRY_NUMBER").and_then(parse_to),
+        part_entry_mm: optional_field(props, "ID_PART_ENTRY_DISK").and_then(empty_str_to_none),
+        size: optional_field(props, "IML_SIZE")
+            .and_then(empty_str_to_none)
+            .and_then(parse_to)
+            .map(|x: u64| x * 512),
+        rotational: optional_field(props, "IML_ROTATIONAL").map(is_one),
+        scsi80: sysfs
+            .scsi80(&devpath)
+            .or_else(|| optional_field(props, "IML_SCSI_80").map(|x| x.trim().to_string())),
+        scsi83: sysfs
+            .scsi83(&devpath)
+            .or_else(|| optional_field(props, "IML_SCSI_83").map(|x| x.trim().to_string())),
+        read_only: sysfs
+            .read_only(&devpath)
+            .or_else(|| optional_field(props, "IML_IS_RO").map(is_one)),
+        bios_boot: optional_field(props, "IML_IS_BIOS_BOOT").map(is_one),
+        zfs_reserved: optional_field(props, "IML_IS_ZFS_RESERVED").map(is_one),
+        is_mpath: optional_field(props, "IML_IS_MPATH").map(is_one),
+        dm_slave_mms: optional_field(props, "DM_UUID")
+            .and_then(|_| sysfs.slave_mms(&devpath))
+            .or_else(|| optional_field(props, "IML_DM_SLAVE_MMS").map(|x| split_space(&x)))
+            .unwrap_or_default(),
+        dm_vg_size: Some(0),
+        md_devs: md_devs(props.clone().into_iter()),
+        dm_multipath_devpath: optional_field(props, "DM_MULTIPATH_DEVICE_PATH").map(is_one),
+        dm_name: optional_field(props, "DM_NAME"),
+        dm_lv_name: optional_field(props, "DM_LV_NAME"),
+        vg_uuid: optional_field(props, "DM_UUID")
+            .and_then(lvm_uuids)
+            .map(|(x, _)| x),
+        lv_uuid: optional_field(props, "DM_UUID")
+            .and_then(lvm_uuids)
+            .map(|(_, y)| y),
+        dm_vg_name: optional_field(props, "DM_VG_NAME"),
+        md_uuid: optional_field(props, "MD_UUID"),
+    })
+}
+
+/// Devices the udev rule skips.
+pub fn is_ignored(devname: &str) -> bool {
+    let name = Path::new(devname)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    ["fd", "loop", "ram"].iter().any(|x| name.starts_with(x))
+        || (name.starts_with("sr") && name.chars().nth(2).map(|x| x.is_ascii_digit()) == Some(true))
+}
+
+/// Builds the `UdevCommand` for the `ACTION` in `props`.
+///
+/// Returns `None` for actions that do not change the block device itself.
+pub fn udev_command(props: &Props, sysfs: &Sysfs) -> Result<Option<UdevCommand>> {
+    let action = required_field(props, "ACTION")?;
+
+    let cmd = match action.as_ref() {
+        "add" => UdevCommand::Add(build_uevent(props, sysfs)?),
+        "change" => UdevCommand::Change(build_uevent(props, sysfs)?),
+        "remove" => UdevCommand::Remove(build_uevent(props, sysfs)?),
+        "move" => UdevCommand::Move(
+            build_uevent(props, sysfs)?,
+            required_field(props, "DEVPATH_OLD")?.into(),
+        ),
+        // Driver (un)binding does not change the block device itself.
+        "bind" | "unbind" => return Ok(None),
+        _ => return Err(Error::UnknownAction(action)),
+    };
+
+    Ok(Some(cmd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use im::ordset;
+
+    #[test]
+    fn test_lvm_uuids() {
+        let input = "LVM-pV8TgNKMJVNrolJgMhVwg4CAeFFAIMC83Ch5TjlWtPw1BCu2ytrGIjlgzeo7oEtu";
+
+        let result = lvm_uuids(input.to_string());
+
+        assert_eq!(
+            result,
+            Some((
+                "pV8TgNKMJVNrolJgMhVwg4CAeFFAIMC8".to_string(),
+                "3Ch5TjlWtPw1BCu2ytrGIjlgzeo7oEtu".to_string()
+            ))
+        )
+    }
+
+    #[test]
+    fn test_md_devs() {
+        let input = vec![
+            ("ACTION".to_string(), "ADD".to_string()),
+            ("DEVLINKS".to_string(), "/dev/disk/by-id/md-name-lotus-32vm6:0 /dev/disk/by-id/md-uuid-685b40ee:f2bc2028:f056f6d2:e292c910".to_string()),
+            ("DEVNAME".to_string(), "/dev/md0".to_string()),
+            ("DEVPATH".to_string(), "/devices/virtual/block/md0".to_string()),
+            ("DEVTYPE".to_string(), "disk".to_string()),
+            ("ID_FS_TYPE".to_string(), "".to_string()),
+            ("IML_IS_RO".to_string(), "0".to_string()),
+            ("IML_SIZE".to_string(), "41910272".to_string()),
+            ("MAJOR".to_string(), "9".to_string()),
+            ("MD_DEVICES".to_string(), "2".to_string()),
+            ("MD_DEVICE_sda_DEV".to_string(), "/dev/sda".to_string()),
+            ("MD_DEVICE_sda_ROLE".to_string(), "0".to_string()),
+            ("MD_DEVICE_sdd_DEV".to_string(), "/dev/sdd".to_string()),
+            ("MD_DEVICE_sdd_ROLE".to_string(), "1".to_string()),
+            ("MD_LEVEL".to_string(), "raid0".to_string()),
+            ("MD_METADATA".to_string(), "1.2".to_string()),
+            ("MD_NAME".to_string(), "lotus-32vm6:0".to_string()),
+            ("MD_UUID".to_string(), "685b40ee:f2bc2028:f056f6d2:e292c910".to_string()),
+            ("MINOR".to_string(), "0".to_string()),
+            ("MPATH_SBIN_PATH".to_string(), "/sbin".to_string()),
+            ("SUBSYSTEM".to_string(), "block".to_string()),
+            ("TAGS".to_string(), ":systemd:".to_string()),
+            ("USEC_INITIALIZED".to_string(), "426309440135".to_string()),
+        ];
+
+        let result = md_devs(input.into_iter());
+
+        assert_eq!(result, ordset!["/dev/sda".into(), "/dev/sdd".into()]);
+    }
+
+    fn props(xs: &[(&str, &str)]) -> Props {
+        xs.iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect()
+    }
+
+    fn no_sysfs() -> Sysfs {
+        Sysfs::new("/nonexistent")
+    }
+
+    fn dm_props(action: &str) -> Props {
+        props(&[
+            ("ACTION", action),
+            (
+                "DEVLINKS",
+                "/dev/mapper/mpathd /dev/disk/by-id/dm-name-mpathd",
+            ),
+            ("DEVNAME", "/dev/dm-3"),
+            ("DEVPATH", "/devices/virtual/block/dm-3"),
+            ("DEVPATH_OLD", "/devices/virtual/block/dm-2"),
+            ("DEVTYPE", "disk"),
+            ("MAJOR", "253"),
+            ("MINOR", "3"),
+            ("SEQNUM", "4021"),
+            ("IML_SIZE", "2048"),
+        ])
+    }
+
+    #[test]
+    fn test_udev_command() {
+        let cmd = udev_command(&dm_props("move"), &no_sysfs()).unwrap();
+
+        match cmd {
+            Some(UdevCommand::Move(x, old)) => {
+                assert_eq!(x.devpath, Path::new("/devices/virtual/block/dm-3"));
+                assert_eq!(x.seqnum, 4021);
+                assert_eq!(x.size, Some(1_048_576));
+                assert_eq!(x.paths.len(), 3);
+                assert_eq!(old, Path::new("/devices/virtual/block/dm-2"));
+            }
+            x => panic!("Expected a Move, got {:?}", x),
+        }
+
+        assert_eq!(
+            udev_command(&dm_props("unbind"), &no_sysfs()).unwrap(),
+            None
+        );
+
+        match udev_command(&dm_props("offline"), &no_sysfs()) {
+            Err(Error::UnknownAction(x)) => assert_eq!(x, "offline"),
+            x => panic!("Expected UnknownAction, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let mut xs = dm_props("add");
+        xs.remove("MAJOR");
+
+        match udev_command(&xs, &no_sysfs()) {
+            Err(Error::MissingField(x)) => assert_eq!(x, "MAJOR"),
+            x => panic!("Expected MissingField, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        assert!(is_ignored("/dev/loop0"));
+        assert!(is_ignored("/dev/sr0"));
+        assert!(!is_ignored("/dev/sra"));
+        assert!(!is_ignored("/dev/sda"));
+    }
+}
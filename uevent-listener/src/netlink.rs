@@ -0,0 +1,307 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Subscribes to processed uevents broadcast by udevd over netlink.
+//!
+//! udevd re-broadcasts each event after running its rules, so the
+//! properties include everything set by `99-iml-device-scanner.rules`.
+//!
+//! Any process may send to the group, so like libudev, only messages from a
+//! userspace sender whose credentials the kernel vouches for as root are kept.
+
+use crate::Props;
+use libc::{c_int, c_void, sockaddr, sockaddr_nl, socklen_t, ucred};
+use std::{io, mem, os::unix::io::RawFd};
+
+/// The multicast group udevd broadcasts processed events on.
+const UDEV_GROUP: u32 = 2;
+
+const PREFIX: &[u8] = b"libudev\0";
+const MAGIC: u32 = 0xfeed_cafe;
+
+/// `sizeof(struct udev_monitor_netlink_header)`
+const HEADER_LEN: usize = 40;
+
+const RCVBUF_SIZE: c_int = 128 * 1024 * 1024;
+
+const BUF_SIZE: usize = 8192;
+
+/// Room for `CMSG_SPACE(sizeof(struct ucred))`, in words so it is aligned for a `cmsghdr`.
+const CONTROL_WORDS: usize = 4;
+
+pub struct Monitor {
+    fd: RawFd,
+}
+
+impl Monitor {
+    /// Opens a netlink socket bound to the udev multicast group.
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let monitor = Monitor { fd };
+
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = UDEV_GROUP;
+
+        let r = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as socklen_t,
+            )
+        };
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Events arrive in bursts (i.e. when a multipath map comes up),
+        // so ask for a receive buffer as large as udevd's own.
+        let r = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUFFORCE,
+                &RCVBUF_SIZE as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+
+        if r < 0 {
+            tracing::warn!(
+                "Could not grow netlink receive buffer: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        // Have the kernel attach the sender's credentials to each message.
+        let on: c_int = 1;
+
+        let r = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &on as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(monitor)
+    }
+
+    /// Blocks until the next message arrives.
+    ///
+    /// Returns `None` for messages that are not udev events, or that were not sent by root.
+    /// An `ENOBUFS` error means events were dropped, as does an `InvalidData` error
+    /// for a message too large for the buffer.
+    pub fn recv(&self) -> io::Result<Option<Props>> {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        let mut control = [0u64; CONTROL_WORDS];
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+
+        loop {
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = &mut addr as *mut sockaddr_nl as *mut c_void;
+            msg.msg_namelen = mem::size_of::<sockaddr_nl>() as socklen_t;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+
+            // With `MSG_TRUNC`, the full length of a message is returned even if it did not fit.
+            let n = unsafe { libc::recvmsg(self.fd, &mut msg, libc::MSG_TRUNC) };
+
+            if n < 0 {
+                let e = io::Error::last_os_error();
+
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                return Err(e);
+            }
+
+            let n = n as usize;
+
+            if n > BUF_SIZE || msg.msg_flags & libc::MSG_TRUNC != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Dropped a {} byte message, larger than the {} byte buffer",
+                        n, BUF_SIZE
+                    ),
+                ));
+            }
+
+            if !is_from_udevd(addr.nl_pid, credentials(&msg)) {
+                tracing::debug!(
+                    "Dropping a netlink message not sent by root's udevd (pid {})",
+                    addr.nl_pid
+                );
+
+                return Ok(None);
+            }
+
+            return Ok(parse_message(&buf[..n]));
+        }
+    }
+}
+
+/// The `SCM_CREDENTIALS` of a received message, if the kernel attached them.
+fn credentials(msg: &libc::msghdr) -> Option<ucred> {
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+
+    if cmsg.is_null() {
+        return None;
+    }
+
+    let cmsg = unsafe { &*cmsg };
+
+    if cmsg.cmsg_level != libc::SOL_SOCKET || cmsg.cmsg_type != libc::SCM_CREDENTIALS {
+        return None;
+    }
+
+    Some(unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const ucred) })
+}
+
+/// Whether a message was sent from userspace (a kernel message has `nl_pid` 0)
+/// by a root process.
+fn is_from_udevd(nl_pid: u32, cred: Option<ucred>) -> bool {
+    match cred {
+        Some(x) => nl_pid != 0 && x.uid == 0,
+        None => false,
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<[u8; 4]> {
+    let xs = buf.get(offset..offset + 4)?;
+
+    let mut x = [0; 4];
+    x.copy_from_slice(xs);
+
+    Some(x)
+}
+
+/// Parses a message sent by udevd.
+///
+/// The message is a `udev_monitor_netlink_header` followed by
+/// NUL separated `KEY=VALUE` properties.
+pub fn parse_message(buf: &[u8]) -> Option<Props> {
+    if buf.len() < HEADER_LEN || !buf.starts_with(PREFIX) {
+        return None;
+    }
+
+    if u32::from_be_bytes(read_u32(buf, 8)?) != MAGIC {
+        return None;
+    }
+
+    let offset = u32::from_ne_bytes(read_u32(buf, 16)?) as usize;
+    let len = u32::from_ne_bytes(read_u32(buf, 20)?) as usize;
+
+    let props = buf
+        .get(offset..offset + len)?
+        .split(|x| *x == 0)
+        .filter_map(|x| {
+            let x = String::from_utf8_lossy(x);
+            let mut xs = x.splitn(2, '=');
+
+            match (xs.next(), xs.next()) {
+                (Some(k), Some(v)) => Some((k.to_string(), v.to_string())),
+                _ => None,
+            }
+        })
+        .collect();
+
+    Some(props)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(props: &[&str]) -> Vec<u8> {
+        let body: Vec<u8> = props
+            .iter()
+            .flat_map(|x| x.bytes().chain(std::iter::once(0)))
+            .collect();
+
+        let mut buf = PREFIX.to_vec();
+        buf.extend_from_slice(&MAGIC.to_be_bytes());
+        buf.extend_from_slice(&(HEADER_LEN as u32).to_ne_bytes());
+        buf.extend_from_slice(&(HEADER_LEN as u32).to_ne_bytes());
+        buf.extend_from_slice(&(body.len() as u32).to_ne_bytes());
+        buf.resize(HEADER_LEN, 0);
+        buf.extend(body);
+
+        buf
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let buf = message(&[
+            "ACTION=change",
+            "DEVPATH=/devices/virtual/block/dm-3",
+            "SUBSYSTEM=block",
+            "DM_UUID=mpath-3600140550e41a841db244a992c31e7df",
+        ]);
+
+        let props = parse_message(&buf).unwrap();
+
+        assert_eq!(props.len(), 4);
+        assert_eq!(props["ACTION"], "change");
+        assert_eq!(props["DM_UUID"], "mpath-3600140550e41a841db244a992c31e7df");
+    }
+
+    #[test]
+    fn test_is_from_udevd() {
+        let cred = |uid| {
+            Some(ucred {
+                pid: 712,
+                uid,
+                gid: 0,
+            })
+        };
+
+        assert!(is_from_udevd(712, cred(0)));
+        assert!(!is_from_udevd(712, cred(1000)));
+        assert!(!is_from_udevd(0, cred(0)));
+        assert!(!is_from_udevd(712, None));
+    }
+
+    #[test]
+    fn test_parse_kernel_message() {
+        // Messages straight from the kernel use a different format.
+        assert_eq!(
+            parse_message(b"change@/devices/virtual/block/dm-3\0ACTION=change\0"),
+            None
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Builds `UEvent`s straight from sysfs and the udev database.
+//!
+//! Used by `uevent-listener --daemon` when it connects, and by the daemon
+//! to bootstrap and periodically reconcile its `State`.
+
+use crate::{build_uevent, db, is_ignored, sysfs::Sysfs};
+use device_types::udev::Scan;
+use std::{fs, io, path::PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    sysfs: Sysfs,
+    run: PathBuf,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Scanner::new("/sys", "/run")
+    }
+}
+
+impl Scanner {
+    /// `sys` and `run` are the sysfs and `/run` roots, so a fake tree can be used in tests.
+    pub fn new(sys: impl Into<PathBuf>, run: impl Into<PathBuf>) -> Self {
+        Scanner {
+            sysfs: Sysfs::new(sys),
+            run: run.into(),
+        }
+    }
+
+    fn kernel_seqnum(&self) -> i64 {
+        fs::read_to_string(self.sysfs.root().join("kernel/uevent_seqnum"))
+            .ok()
+            .and_then(|x| x.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    pub fn scan(&self) -> io::Result<Scan> {
+        let seqnum = self.kernel_seqnum();
+
+        let present = db::devpaths(self.sysfs.root())?;
+
+        let uevents = db::enumerate(self.sysfs.root(), &self.run)?
+            .into_iter()
+            .filter(|x| !x.get("DEVNAME").map(|x| is_ignored(x)).unwrap_or(true))
+            .filter_map(|mut x| {
+                x.insert("SEQNUM".into(), seqnum.to_string());
+
+                match build_uevent(&x, &self.sysfs) {
+                    Ok(x) => Some((x.devpath.clone(), x)),
+                    Err(e) => {
+                        tracing::warn!("Could not build uevent for {:?}: {}", x.get("DEVPATH"), e);
+
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Ok(Scan {
+            seqnum,
+            uevents,
+            present,
+        })
+    }
+}
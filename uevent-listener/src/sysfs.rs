@@ -0,0 +1,383 @@
+// Copyright (c) 2019 DDN. All rights reserved.
+// Use of this source code is governed by a MIT-style
+// license that can be found in the LICENSE file.
+
+//! Reads device attributes straight from sysfs.
+//!
+//! `scsi80` and `scsi83` are formatted the way `scsi_id -g -p 0x80` and
+//! `scsi_id -g -p 0x83` print them, so existing IML installs see the same serials.
+
+use im::Vector;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Association of a designator with the addressed logical unit.
+const ASSOC_LUN: u8 = 0;
+
+/// Designator types from SPC-4 table 459.
+const TYPE_VENDOR: u8 = 0;
+const TYPE_T10: u8 = 1;
+const TYPE_EUI64: u8 = 2;
+const TYPE_NAA: u8 = 3;
+
+const CODE_SET_ASCII: u8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Sysfs::new("/sys")
+    }
+}
+
+impl Sysfs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Sysfs { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The sysfs directory for a `DEVPATH`.
+    fn dir(&self, devpath: &Path) -> PathBuf {
+        self.root.join(devpath.strip_prefix("/").unwrap_or(devpath))
+    }
+
+    /// The major:minor numbers of the devices underlying a dm device.
+    pub fn slave_mms(&self, devpath: &Path) -> Option<Vector<String>> {
+        let mut xs: Vec<String> = slaves(&self.dir(devpath))?
+            .iter()
+            .filter_map(|x| read_attr(&x.join("dev")))
+            .collect();
+
+        xs.sort();
+
+        Some(xs.into_iter().collect())
+    }
+
+    pub fn read_only(&self, devpath: &Path) -> Option<bool> {
+        read_attr(&self.dir(devpath).join("ro")).map(|x| x == "1")
+    }
+
+    pub fn scsi80(&self, devpath: &Path) -> Option<String> {
+        let dir = scsi_device_dir(&self.dir(devpath), "vpd_pg80")?;
+
+        let vendor = read_raw_attr(&dir.join("vendor")).unwrap_or_default();
+        let model = read_raw_attr(&dir.join("model")).unwrap_or_default();
+
+        let serial = parse_pg80(&fs::read(dir.join("vpd_pg80")).ok()?)?;
+
+        Some(
+            format!("S{:<8}{:<16}{}", vendor, model, serial)
+                .trim()
+                .to_string(),
+        )
+    }
+
+    pub fn scsi83(&self, devpath: &Path) -> Option<String> {
+        let dir = scsi_device_dir(&self.dir(devpath), "vpd_pg83")?;
+
+        parse_pg83(&fs::read(dir.join("vpd_pg83")).ok()?)
+    }
+}
+
+/// Reads an attribute, dropping the trailing newline but keeping any padding.
+fn read_raw_attr(p: &Path) -> Option<String> {
+    fs::read_to_string(p)
+        .ok()
+        .map(|x| x.trim_end_matches('\n').to_string())
+}
+
+fn read_attr(p: &Path) -> Option<String> {
+    read_raw_attr(p).map(|x| x.trim().to_string())
+}
+
+fn slaves(dir: &Path) -> Option<Vec<PathBuf>> {
+    let mut xs: Vec<PathBuf> = fs::read_dir(dir.join("slaves"))
+        .ok()?
+        .filter_map(|x| x.ok())
+        .filter_map(|x| fs::canonicalize(x.path()).ok())
+        .collect();
+
+    xs.sort();
+
+    Some(xs)
+}
+
+/// Finds the SCSI device that answers inquiries for the block device at `dir`.
+///
+/// Partitions are answered by their disk. Multipath maps (and kpartx partitions
+/// of them) are answered by their first slave, as every path reports the same LUN.
+/// Other dm targets such as LVs may span several disks, so they have no answer.
+fn scsi_device_dir(dir: &Path, page: &str) -> Option<PathBuf> {
+    let device = dir.join("device");
+
+    if device.join(page).exists() {
+        return Some(device);
+    }
+
+    if dir.join("partition").exists() {
+        return scsi_device_dir(dir.parent()?, page);
+    }
+
+    if is_mpath(dir) {
+        return scsi_device_dir(slaves(dir)?.first()?, page);
+    }
+
+    None
+}
+
+fn is_mpath(dir: &Path) -> bool {
+    read_attr(&dir.join("dm/uuid"))
+        .map(|x| x.starts_with("mpath-") || (x.starts_with("part") && x.contains("-mpath-")))
+        .unwrap_or(false)
+}
+
+/// Parses the unit serial number out of VPD page 0x80.
+pub fn parse_pg80(buf: &[u8]) -> Option<String> {
+    if buf.get(1) != Some(&0x80) {
+        return None;
+    }
+
+    let len = *buf.get(3)? as usize;
+
+    let serial = buf.get(4..4 + len)?;
+
+    Some(String::from_utf8_lossy(serial).to_string())
+}
+
+/// Ranks designators the way `scsi_id` does; lower is preferred.
+fn rank(id_type: u8, data: &[u8]) -> Option<u8> {
+    match id_type {
+        TYPE_NAA => match data.first()? >> 4 {
+            6 => Some(0),
+            5 => Some(1),
+            2 => Some(2),
+            3 => Some(3),
+            _ => None,
+        },
+        TYPE_EUI64 => Some(4),
+        TYPE_T10 => Some(5),
+        TYPE_VENDOR => Some(6),
+        _ => None,
+    }
+}
+
+/// Picks the preferred logical unit designator out of VPD page 0x83.
+///
+/// The result is prefixed with the designator type and hex encoded,
+/// unless the designator is ASCII.
+pub fn parse_pg83(buf: &[u8]) -> Option<String> {
+    if buf.get(1) != Some(&0x83) || buf.len() < 4 {
+        return None;
+    }
+
+    let len = (((buf[2] as usize) << 8) | buf[3] as usize) + 4;
+    let buf = &buf[..len.min(buf.len())];
+
+    let mut best: Option<(u8, String)> = None;
+    let mut idx = 4;
+
+    while idx + 4 <= buf.len() {
+        let code_set = buf[idx] & 0x0f;
+        let assoc = (buf[idx + 1] >> 4) & 0x03;
+        let id_type = buf[idx + 1] & 0x0f;
+        let data = buf.get(idx + 4..idx + 4 + buf[idx + 3] as usize)?;
+
+        idx += 4 + data.len();
+
+        if assoc != ASSOC_LUN || data.is_empty() {
+            continue;
+        }
+
+        let r = match rank(id_type, data) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        if let Some((x, _)) = &best {
+            if r >= *x {
+                continue;
+            }
+        }
+
+        let id = if code_set == CODE_SET_ASCII {
+            String::from_utf8_lossy(data).trim().to_string()
+        } else {
+            data.iter().map(|x| format!("{:02x}", x)).collect()
+        };
+
+        best = Some((r, format!("{}{}", id_type, id)));
+    }
+
+    best.map(|(_, x)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    const NAA: [u8; 16] = [
+        0x60, 0x01, 0x40, 0x55, 0x0e, 0x41, 0xa8, 0x41, 0xdb, 0x24, 0x4a, 0x99, 0x2c, 0x31, 0xe7,
+        0xdf,
+    ];
+
+    fn pg80(serial: &str) -> Vec<u8> {
+        let mut buf = vec![0x00, 0x80, 0x00, serial.len() as u8];
+        buf.extend(serial.bytes());
+
+        buf
+    }
+
+    fn designator(code_set: u8, assoc: u8, id_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![code_set, (assoc << 4) | id_type, 0x00, data.len() as u8];
+        buf.extend_from_slice(data);
+
+        buf
+    }
+
+    fn pg83(designators: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = designators.concat();
+
+        let mut buf = vec![0x00, 0x83, 0x00, body.len() as u8];
+        buf.extend(body);
+
+        buf
+    }
+
+    fn lio_pg83() -> Vec<u8> {
+        pg83(&[
+            designator(
+                CODE_SET_ASCII,
+                ASSOC_LUN,
+                TYPE_T10,
+                b"LIO-ORG 50e41a84-1db2-44a9-92c3-1e7dfad48fce",
+            ),
+            // The target port, which must not be picked.
+            designator(1, 1, 4, &[0x00, 0x00, 0x00, 0x01]),
+            designator(1, ASSOC_LUN, TYPE_NAA, &NAA),
+        ])
+    }
+
+    fn write(p: &Path, x: &[u8]) {
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, x).unwrap();
+    }
+
+    #[test]
+    fn test_parse_pg80() {
+        assert_eq!(
+            parse_pg80(&pg80("50e41a84-1db2-44a9-92c3-1e7dfad48fce")),
+            Some("50e41a84-1db2-44a9-92c3-1e7dfad48fce".to_string())
+        );
+        assert_eq!(parse_pg80(&[0x00, 0x83, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_parse_pg83() {
+        assert_eq!(
+            parse_pg83(&lio_pg83()),
+            Some("3600140550e41a841db244a992c31e7df".to_string())
+        );
+
+        let t10 = pg83(&[designator(
+            CODE_SET_ASCII,
+            ASSOC_LUN,
+            TYPE_T10,
+            b"ATA     VBOX HARDDISK VB1c9a3c1c",
+        )]);
+
+        assert_eq!(
+            parse_pg83(&t10),
+            Some("1ATA     VBOX HARDDISK VB1c9a3c1c".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sysfs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+
+        let sda = root.join("devices/platform/host0/session1/target0:0:0/0:0:0:0/block/sda");
+        let sdb = root.join("devices/platform/host1/session2/target1:0:0/1:0:0:0/block/sdb");
+        let dm = root.join("devices/virtual/block/dm-0");
+        let lv = root.join("devices/virtual/block/dm-1");
+        let mpath1 = root.join("devices/virtual/block/dm-2");
+
+        for (x, dev) in &[(&sda, "8:0"), (&sdb, "8:16")] {
+            write(&x.join("dev"), format!("{}\n", dev).as_bytes());
+            write(&x.join("ro"), b"0\n");
+            write(&x.join("device/vendor"), b"LIO-ORG \n");
+            write(&x.join("device/model"), b"ost12           \n");
+            write(
+                &x.join("device/vpd_pg80"),
+                &pg80("50e41a84-1db2-44a9-92c3-1e7dfad48fce"),
+            );
+            write(&x.join("device/vpd_pg83"), &lio_pg83());
+        }
+
+        write(&sda.join("sda1/partition"), b"1\n");
+        write(&sda.join("sda1/ro"), b"1\n");
+
+        write(
+            &dm.join("dm/uuid"),
+            b"mpath-3600140550e41a841db244a992c31e7df\n",
+        );
+        write(&dm.join("ro"), b"0\n");
+        fs::create_dir_all(dm.join("slaves")).unwrap();
+        symlink(&sdb, dm.join("slaves/sdb")).unwrap();
+        symlink(&sda, dm.join("slaves/sda")).unwrap();
+
+        write(
+            &lv.join("dm/uuid"),
+            b"LVM-Wq2Oj4Xz0Q7Kf7bXcSrnDPxSTLZwJ1bYAz7mBo5cHQ2iuqX6KcZNnyeCgRYzDRDr\n",
+        );
+        fs::create_dir_all(lv.join("slaves")).unwrap();
+        symlink(&sda, lv.join("slaves/sda")).unwrap();
+
+        write(
+            &mpath1.join("dm/uuid"),
+            b"part1-mpath-3600140550e41a841db244a992c31e7df\n",
+        );
+        fs::create_dir_all(mpath1.join("slaves")).unwrap();
+        symlink(&dm, mpath1.join("slaves/dm-0")).unwrap();
+
+        let sysfs = Sysfs::new(root);
+
+        let sda1 = Path::new("/devices/platform/host0/session1/target0:0:0/0:0:0:0/block/sda/sda1");
+        let dm = Path::new("/devices/virtual/block/dm-0");
+
+        assert_eq!(sysfs.read_only(sda1), Some(true));
+        assert_eq!(sysfs.read_only(dm), Some(false));
+
+        assert_eq!(
+            sysfs.scsi80(sda1),
+            Some("SLIO-ORG ost12           50e41a84-1db2-44a9-92c3-1e7dfad48fce".to_string())
+        );
+        assert_eq!(
+            sysfs.scsi83(dm),
+            Some("3600140550e41a841db244a992c31e7df".to_string())
+        );
+        assert_eq!(
+            sysfs.scsi83(Path::new("/devices/virtual/block/dm-2")),
+            Some("3600140550e41a841db244a992c31e7df".to_string())
+        );
+        assert_eq!(sysfs.scsi83(Path::new("/devices/virtual/block/dm-1")), None);
+
+        assert_eq!(
+            sysfs.slave_mms(dm),
+            Some(im::vector!["8:0".to_string(), "8:16".to_string()])
+        );
+        assert_eq!(
+            sysfs.slave_mms(Path::new("/devices/virtual/block/dm-3")),
+            None
+        );
+    }
+}
@@ -7,7 +7,6 @@ use futures::TryStreamExt;
 use std::{error, fmt, io, num, result};
 use tokio::{
     codec::{FramedRead, LinesCodec, LinesCodecError},
-    io::{AsyncWrite, AsyncWriteExt},
     net::UnixStream,
 };
 
@@ -20,6 +19,7 @@ pub enum Error {
     LibZfsError(libzfs::LibZfsError),
     ParseIntError(num::ParseIntError),
     LinesCodecError(LinesCodecError),
+    ClientError(device_scanner_client::Error),
 }
 
 impl fmt::Display for Error {
@@ -30,6 +30,7 @@ impl fmt::Display for Error {
             Error::LibZfsError(ref err) => write!(f, "{}", err),
             Error::ParseIntError(ref err) => write!(f, "{}", err),
             Error::LinesCodecError(ref err) => write!(f, "{}", err),
+            Error::ClientError(ref err) => write!(f, "{}", err),
         }
     }
 }
@@ -42,6 +43,7 @@ impl error::Error for Error {
             Error::LibZfsError(ref err) => Some(err),
             Error::ParseIntError(ref err) => Some(err),
             Error::LinesCodecError(ref err) => Some(err),
+            Error::ClientError(ref err) => Some(err),
         }
     }
 }
@@ -93,23 +95,10 @@ pub fn handle_zed_commands(cmd: ZedCommand) -> Result<PoolCommand> {
     }
 }
 
-/// Creates a stream that implements `AsyncWrite`
-/// In this case, we use `tokio::net::UnixStream`,
-/// But we can substitute this fn for integration testing
-pub async fn get_write_stream() -> Result<impl AsyncWrite> {
-    Ok(UnixStream::connect("/var/run/device-scanner.sock").await?)
-}
-
 pub async fn send_to_device_scanner(pool_command: PoolCommand) -> Result<()> {
-    let pool_command_str =
-        serde_json::to_string(&device_types::Command::PoolCommand(pool_command))?;
-
-    tracing::debug!("Sending: {:?}", pool_command_str);
+    tracing::debug!("Sending: {:?}", pool_command);
 
-    get_write_stream()
-        .await?
-        .write_all(pool_command_str.as_bytes())
-        .await?;
+    device_scanner_client::send(device_types::Command::PoolCommand(pool_command)).await?;
 
     Ok(())
 }